@@ -0,0 +1,382 @@
+// jkcoxson
+// Makes sure a TCP component knows its key without sending it
+
+// When a component connects, the core sends it a challenge with a fresh random nonce
+// The component answers with the HMAC-SHA256 of the nonce, keyed with its key from the config, both in hex
+// The nonce is never reused, so an answer that was listened in on can't be replayed
+// Components from before challenges send their key as the first line instead, which only works if the config allows it
+
+use hmac::{Hmac, KeyInit, Mac};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use std::{collections::HashMap, sync::Arc, time::Duration};
+use subtle::ConstantTimeEq;
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    sync::{mpsc::UnboundedSender, Mutex},
+};
+
+use crate::{component::Component, tls::Connection};
+
+// How long a component gets to answer the challenge
+pub const AUTH_TIMEOUT: Duration = Duration::from_secs(10);
+
+// The longest answer the core will read, nothing legitimate comes close
+const MAX_AUTH_LINE: usize = 1024;
+
+#[derive(Serialize)]
+pub struct ChallengePacket {
+    #[serde(rename = "type")]
+    pub type_: String,
+    pub nonce: String,
+}
+
+#[derive(Deserialize)]
+pub struct AuthPacket {
+    pub hmac: String,
+}
+
+#[derive(Serialize)]
+pub struct AuthErrorPacket {
+    #[serde(rename = "type")]
+    pub type_: String,
+    pub message: String,
+}
+
+/// Challenges a new connection and works out which component it is
+/// # Arguments
+/// * `socket` - The new connection
+/// * `component_arc` - The components, to get their keys from
+/// * `network_arc` - The components waiting for a connection, by name
+/// * `allow_plaintext` - Whether components can still send their key as the first line
+/// # Returns
+/// * Where to hand the connection to, or why it was turned away. The reason never contains anything the component sent.
+pub async fn authenticate(
+    socket: &mut Connection,
+    component_arc: &Arc<Mutex<HashMap<String, Component>>>,
+    network_arc: &Arc<Mutex<HashMap<String, UnboundedSender<Connection>>>>,
+    allow_plaintext: bool,
+) -> Result<UnboundedSender<Connection>, String> {
+    let nonce = nonce()?;
+    let challenge = serde_json::to_string(&ChallengePacket {
+        type_: "challenge".to_string(),
+        nonce: nonce.clone(),
+    })
+    .map_err(|e| e.to_string())?;
+    socket
+        .write_all(format!("{}\n", challenge).as_bytes())
+        .await
+        .map_err(|e| format!("failed to send the challenge: {}", e))?;
+    socket
+        .flush()
+        .await
+        .map_err(|e| format!("failed to send the challenge: {}", e))?;
+
+    let answer = match tokio::time::timeout(AUTH_TIMEOUT, read_line(socket)).await {
+        Ok(answer) => answer?,
+        Err(_) => return Err("did not answer the challenge in time".to_string()),
+    };
+
+    // Components without a key can only connect by certificate or as a Unix socket peer
+    let keys: Vec<(String, String)> = component_arc
+        .lock()
+        .await
+        .iter()
+        .filter(|(_, component)| !component.key.is_empty())
+        .map(|(name, component)| (name.clone(), component.key.clone()))
+        .collect();
+
+    let result = match serde_json::from_str::<AuthPacket>(&answer) {
+        Ok(auth) => match decode_hex(&auth.hmac) {
+            Some(hmac) => {
+                // Every key is checked so that how long this takes doesn't give away which one matched
+                let mut found = None;
+                for (name, key) in keys.iter() {
+                    if verify(key, &nonce, &hmac) && found.is_none() {
+                        found = Some(name);
+                    }
+                }
+                match found {
+                    Some(name) => waiting(name, network_arc).await,
+                    None => Err("answered the challenge with the wrong key".to_string()),
+                }
+            }
+            None => Err("sent an answer that isn't hex".to_string()),
+        },
+        Err(_) if allow_plaintext => {
+            let mut found = None;
+            for (name, key) in keys.iter() {
+                if bool::from(key.as_bytes().ct_eq(answer.as_bytes())) && found.is_none() {
+                    found = Some(name);
+                }
+            }
+            match found {
+                Some(name) => waiting(name, network_arc).await,
+                None => Err("sent an unknown plaintext key".to_string()),
+            }
+        }
+        Err(_) => Err(
+            "sent a plaintext key, set allow_plaintext_keys in the config to accept those"
+                .to_string(),
+        ),
+    };
+
+    if let Err(reason) = &result {
+        let to_send = serde_json::to_string(&AuthErrorPacket {
+            type_: "auth_error".to_string(),
+            message: reason.clone(),
+        })
+        .map_err(|e| e.to_string())?;
+        // Don't care, we are hanging up anyway
+        let _ = socket.write_all(format!("{}\n", to_send).as_bytes()).await;
+        let _ = socket.flush().await;
+    }
+    result
+}
+
+/// Works out which component a new connection belongs to and hands it over, whichever listener it came in on
+/// # Arguments
+/// * `connection` - The new connection
+/// * `name` - The component its TLS client certificate or Unix socket user is mapped to, None to challenge it
+/// * `component_arc` - The components, to get their keys from
+/// * `network_arc` - The components waiting for a connection, by name
+/// * `allow_plaintext` - Whether components can still send their key as the first line
+/// # Returns
+/// * Why the connection was turned away, if it was
+pub async fn hand_off(
+    mut connection: Connection,
+    name: Option<String>,
+    component_arc: &Arc<Mutex<HashMap<String, Component>>>,
+    network_arc: &Arc<Mutex<HashMap<String, UnboundedSender<Connection>>>>,
+    allow_plaintext: bool,
+) -> Result<(), String> {
+    let sender = match name {
+        Some(name) => by_name(&name, component_arc, network_arc).await?,
+        None => authenticate(&mut connection, component_arc, network_arc, allow_plaintext).await?,
+    };
+    // Don't care, the component is being removed if this fails
+    let _ = sender.send(connection);
+    Ok(())
+}
+
+/// Finds the component a connection belongs to without a challenge, by its TLS client certificate or Unix socket user
+/// # Arguments
+/// * `name` - The component the certificate or user is mapped to in the config
+/// # Returns
+/// * Where to hand the connection to, or why it was turned away
+pub async fn by_name(
+    name: &str,
+    component_arc: &Arc<Mutex<HashMap<String, Component>>>,
+    network_arc: &Arc<Mutex<HashMap<String, UnboundedSender<Connection>>>>,
+) -> Result<UnboundedSender<Connection>, String> {
+    if !component_arc.lock().await.contains_key(name) {
+        return Err(format!("belongs to {}, which isn't loaded", name));
+    }
+    waiting(name, network_arc).await
+}
+
+/// Finds where to hand a component's connection to
+async fn waiting(
+    name: &str,
+    network_arc: &Arc<Mutex<HashMap<String, UnboundedSender<Connection>>>>,
+) -> Result<UnboundedSender<Connection>, String> {
+    match network_arc.lock().await.get(name) {
+        Some(sender) => Ok(sender.clone()),
+        None => Err(format!(
+            "belongs to {}, which isn't waiting for a connection",
+            name
+        )),
+    }
+}
+
+/// Whether an answer to a challenge was made with a key, compared in constant time
+fn verify(key: &str, nonce: &str, answer: &[u8]) -> bool {
+    let mut mac = match Hmac::<Sha256>::new_from_slice(key.as_bytes()) {
+        Ok(mac) => mac,
+        Err(_) => return false,
+    };
+    mac.update(nonce.as_bytes());
+    mac.verify_slice(answer).is_ok()
+}
+
+/// Makes a random nonce for a challenge, in hex
+fn nonce() -> Result<String, String> {
+    let mut bytes = [0u8; 32];
+    getrandom::fill(&mut bytes).map_err(|e| format!("failed to make a nonce: {}", e))?;
+    Ok(bytes.iter().map(|b| format!("{:02x}", b)).collect())
+}
+
+fn decode_hex(hex: &str) -> Option<Vec<u8>> {
+    // from_str_radix would let a + sign through
+    if !hex.len().is_multiple_of(2) || !hex.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+/// Reads the answer to the challenge a byte at a time
+/// Nothing past the newline can be read, it belongs to the component once it is running
+async fn read_line(socket: &mut Connection) -> Result<String, String> {
+    let mut line = vec![];
+    loop {
+        let byte = socket
+            .read_u8()
+            .await
+            .map_err(|_| "hung up before answering the challenge".to_string())?;
+        if byte == b'\n' {
+            break;
+        }
+        line.push(byte);
+        if line.len() > MAX_AUTH_LINE {
+            return Err("sent an answer that is too long".to_string());
+        }
+    }
+    Ok(String::from_utf8_lossy(&line).trim().to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::{
+        io::{AsyncBufReadExt, BufReader},
+        net::{TcpListener, TcpStream},
+        sync::mpsc::unbounded_channel,
+    };
+
+    // RFC 4231, test case 2
+    const RFC_ANSWER: &str = "5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843";
+
+    #[test]
+    fn verify_known_answer() {
+        let answer = decode_hex(RFC_ANSWER).unwrap();
+        assert!(verify("Jefe", "what do ya want for nothing?", &answer));
+    }
+
+    #[test]
+    fn verify_wrong_key() {
+        let answer = decode_hex(RFC_ANSWER).unwrap();
+        assert!(!verify("Jeff", "what do ya want for nothing?", &answer));
+        assert!(!verify("Jefe", "what do ya want for something?", &answer));
+        assert!(!verify(
+            "Jefe",
+            "what do ya want for nothing?",
+            &answer[..31]
+        ));
+    }
+
+    #[test]
+    fn decode_hex_rejects_bad_input() {
+        assert_eq!(decode_hex("00ff7A"), Some(vec![0x00, 0xff, 0x7a]));
+        assert_eq!(decode_hex("abc"), None);
+        assert_eq!(decode_hex("zz"), None);
+        assert_eq!(decode_hex("+1"), None);
+        // Not ASCII, so two bytes at a time lands inside the character
+        assert_eq!(decode_hex("é0"), None);
+    }
+
+    /// Connects a component with the key "secret" and has the core authenticate it
+    /// # Arguments
+    /// * `answer` - What the component answers the nonce with
+    /// # Returns
+    /// * Whether it got in, and the lines it was sent
+    async fn connect(answer: fn(&str) -> String, allow_plaintext: bool) -> (bool, Vec<String>) {
+        let (sender, _receiver) = unbounded_channel();
+        let (network_sender, _network_receiver) = unbounded_channel();
+        let component_arc = Arc::new(Mutex::new(HashMap::from([(
+            "plugin".to_string(),
+            Component::new("plugin".to_string(), 1, "secret".to_string(), sender),
+        )])));
+        let network_arc = Arc::new(Mutex::new(HashMap::from([(
+            "plugin".to_string(),
+            network_sender,
+        )])));
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let component = tokio::spawn(async move {
+            let mut stream = BufReader::new(TcpStream::connect(address).await.unwrap());
+            let mut lines: Vec<String> = vec![];
+            loop {
+                let mut line = String::new();
+                if stream.read_line(&mut line).await.unwrap_or(0) == 0 {
+                    break;
+                }
+                if lines.is_empty() {
+                    let challenge: serde_json::Value = serde_json::from_str(&line).unwrap();
+                    let to_send = answer(challenge["nonce"].as_str().unwrap());
+                    stream
+                        .get_mut()
+                        .write_all(format!("{}\n", to_send).as_bytes())
+                        .await
+                        .unwrap();
+                }
+                lines.push(line.trim().to_string());
+            }
+            lines
+        });
+
+        let (socket, _) = listener.accept().await.unwrap();
+        let mut connection = Connection::Plain(socket);
+        let result = authenticate(
+            &mut connection,
+            &component_arc,
+            &network_arc,
+            allow_plaintext,
+        )
+        .await;
+        drop(connection);
+        (result.is_ok(), component.await.unwrap())
+    }
+
+    fn hmac_answer(key: &str, nonce: &str) -> String {
+        let mut mac = Hmac::<Sha256>::new_from_slice(key.as_bytes()).unwrap();
+        mac.update(nonce.as_bytes());
+        let hmac: String = mac
+            .finalize()
+            .into_bytes()
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect();
+        format!("{{\"type\":\"auth\",\"hmac\":\"{}\"}}", hmac)
+    }
+
+    #[tokio::test]
+    async fn challenge_with_right_key() {
+        let (ok, lines) = connect(|nonce| hmac_answer("secret", nonce), false).await;
+        assert!(ok);
+        assert_eq!(lines.len(), 1);
+        assert!(lines[0].contains("\"type\":\"challenge\""));
+    }
+
+    #[tokio::test]
+    async fn challenge_with_wrong_key() {
+        let (ok, lines) = connect(|nonce| hmac_answer("guess", nonce), false).await;
+        assert!(!ok);
+        assert!(lines[1].contains("\"type\":\"auth_error\""));
+        assert!(lines[1].contains("wrong key"));
+    }
+
+    #[tokio::test]
+    async fn challenge_with_bad_hex() {
+        let (ok, lines) = connect(|_| "{\"hmac\":\"abc\"}".to_string(), false).await;
+        assert!(!ok);
+        assert!(lines[1].contains("isn't hex"));
+    }
+
+    #[tokio::test]
+    async fn plaintext_key_rejected_unless_allowed() {
+        let (ok, lines) = connect(|_| "secret".to_string(), false).await;
+        assert!(!ok);
+        assert!(lines[1].contains("allow_plaintext_keys"));
+
+        let (ok, _) = connect(|_| "secret".to_string(), true).await;
+        assert!(ok);
+
+        let (ok, lines) = connect(|_| "guess".to_string(), true).await;
+        assert!(!ok);
+        assert!(lines[1].contains("unknown plaintext key"));
+    }
+}
@@ -1,34 +1,142 @@
 // jkcoxson
 // Stores commands and their sources
 
-use serde_json::Value;
-use tokio::{
-    fs::File,
-    io::{AsyncReadExt, AsyncWriteExt},
+use serde_json::{Map, Value};
+use std::{
+    collections::{BTreeMap, HashMap, VecDeque},
+    path::{Path, PathBuf},
+    time::{Duration, Instant},
+};
+
+use crate::{
+    config::{Config, ConflictPolicy},
+    permissions::Permissions,
 };
 
 pub struct Command {
-    pub structure: CommandStructure,
+    pub structure: CommandStructure, // The name in here is what interfaces see, which may be namespaced
     pub source: String,
+    pub name: String, // The name the source registered the command under
+    pub preset: Map<String, Value>, // Options filled in by a macro, empty for everything else
+}
+
+/// Keeps track of which component registered which commands
+/// Sorted so that the command list comes out the same every time
+pub struct CommandRegistry {
+    sources: BTreeMap<String, BTreeMap<String, CommandStructure>>, // Source -> command name -> command
+    order: Vec<String>, // Sources in the order they first registered commands
+    sent: HashMap<String, BTreeMap<String, CommandStructure>>, // Interface -> the commands it was last sent
+    acknowledged: HashMap<String, BTreeMap<String, CommandStructure>>, // Interface -> the commands it last said it has
+    pub conflicts: ConflictPolicy,
+    pub overrides: HashMap<String, String>, // Command name -> source that keeps it in a conflict
+    pub prefix: String, // What plain text messages start with to be parsed as commands
+    pub rate_limits: HashMap<String, RateLimit>, // Command name -> rate limit, replacing the command's own
+    invocations: HashMap<String, (Duration, VecDeque<Instant>)>, // Rate limit bucket -> its window and when it was used
+    swept: Instant, // When buckets that ran out were last thrown away
+    pub permissions: Permissions,
+    pub aliases: BTreeMap<String, String>, // Alias -> the command it stands for
+    pub macros: BTreeMap<String, Macro>,   // Macro name -> what it runs
+    left_out: BTreeMap<String, String>, // Alias or macro -> why it was left out, as last reported
 }
 
+/// A command with some of its options filled in ahead of time, defined in the config
+#[derive(serde::Serialize, serde::Deserialize)]
+pub struct Macro {
+    pub command: String, // The command it runs
+    #[serde(default)]
+    pub options: Map<String, Value>, // The options it fills in, which users can't change
+    #[serde(default)]
+    pub description: std::option::Option<String>, // Defaults to the description of the command
+}
+
+// Bumped whenever the layout of the command cache changes
+const CACHE_VERSION: u32 = 2;
+
+// How often rate limit buckets that have nothing left in their window are thrown away
+const SWEEP_INTERVAL: Duration = Duration::from_secs(60);
+
+/// What gets written to the command cache
 #[derive(serde::Serialize, serde::Deserialize)]
+struct CommandCache {
+    version: u32,
+    order: Vec<String>, // Sources in the order they first registered commands
+    sources: BTreeMap<String, Vec<CommandStructure>>, // Source -> the commands it registered
+    #[serde(default)]
+    interfaces: BTreeMap<String, Vec<CommandStructure>>, // Interface -> the commands it last acknowledged
+}
+
+/// How often a command can be invoked
+#[derive(serde::Serialize, serde::Deserialize, PartialEq)]
+pub struct RateLimit {
+    pub count: u32,  // How many invocations are allowed
+    pub window: u64, // Within this many seconds
+    #[serde(default)]
+    pub scope: RateLimitScope,
+}
+
+#[derive(serde::Serialize, serde::Deserialize, PartialEq, Clone, Copy, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum RateLimitScope {
+    #[default]
+    User, // Each user gets their own allowance, invocations without a user share one
+    Channel, // Each channel gets its own allowance, invocations without a channel share one
+    Global,  // Everyone shares one allowance
+}
+
+/// Why an invocation didn't make it to its plugin
+pub enum InvokeError {
+    Invalid(String),    // Unknown command, bad options, or the plugin is gone
+    Cooldown(Duration), // Rate limited, with how long until it can be invoked again
+    Denied(String),     // The user doesn't have the permission the command needs
+}
+
+/// Two or more sources registering a command with the same name
+pub struct Conflict {
+    pub name: String,
+    pub winner: std::option::Option<String>, // The source that keeps the name, if any
+    pub losers: Vec<String>,                 // The sources that lose it
+}
+
+#[derive(serde::Serialize)]
+pub struct ConflictPacket {
+    #[serde(rename = "type")]
+    pub type_: String,
+    pub name: String,
+    pub winner: std::option::Option<String>,
+    pub renamed: std::option::Option<String>, // What the command is called now, if it wasn't dropped
+}
+
+#[derive(serde::Serialize, serde::Deserialize, PartialEq)]
 pub struct CommandStructure {
     pub name: String,
-    pub plugin: String,
+    #[serde(default)]
+    pub plugin: String, // Filled in by the core with the source if left empty
     pub description: String,
+    #[serde(default)]
     pub options: Vec<Option>,
+    #[serde(default, skip_serializing_if = "std::option::Option::is_none")]
+    pub rate_limit: std::option::Option<RateLimit>,
+    // The permission a user needs to invoke the command
+    #[serde(default, skip_serializing_if = "std::option::Option::is_none")]
+    pub permission: std::option::Option<String>,
+    // Filled in by the core with the command an alias or macro runs
+    #[serde(default, skip_serializing_if = "std::option::Option::is_none")]
+    pub alias: std::option::Option<String>,
 }
 
-#[derive(serde::Serialize, serde::Deserialize)]
+#[derive(serde::Serialize, serde::Deserialize, PartialEq)]
 pub struct Option {
     pub name: String,
+    #[serde(rename = "type", alias = "type_", default)]
+    pub type_: String, // What kind of value the option takes, such as "string", "integer", "number" or "bool"
     pub description: String,
+    #[serde(default)]
     pub required: bool,
+    #[serde(default)]
     pub choices: Vec<Choice>,
 }
 
-#[derive(serde::Serialize, serde::Deserialize)]
+#[derive(serde::Serialize, serde::Deserialize, PartialEq)]
 pub struct Choice {
     pub name: String,
     pub value: Value,
@@ -36,15 +144,108 @@ pub struct Choice {
 
 #[derive(serde::Serialize)]
 pub struct CommandPacket {
+    #[serde(rename = "type")]
     type_: String,
     commands: Vec<CommandStructure>,
 }
 
+/// What changed in the commands since an interface was last sent them
+#[derive(serde::Serialize)]
+pub struct CommandDiffPacket {
+    #[serde(rename = "type")]
+    pub type_: String,
+    pub added: Vec<CommandStructure>,
+    pub removed: Vec<String>,
+    pub changed: Vec<CommandStructure>,
+}
+
+/// A user invoking a command through an interface
+#[derive(serde::Serialize, serde::Deserialize)]
+pub struct Invocation {
+    pub name: String,
+    #[serde(default)]
+    pub options: Map<String, Value>,
+    #[serde(default)]
+    pub nonce: Value, // Handed back in any error so the interface knows which invocation failed
+    #[serde(default)]
+    pub context: Value, // Whatever the interface knows about where it came from, such as the user and channel
+}
+
+/// An invocation on its way to the component that registered the command
+#[derive(serde::Serialize)]
+pub struct InvokePacket {
+    #[serde(rename = "type")]
+    pub type_: String,
+    pub name: String, // The name the component registered the command under
+    pub options: Map<String, Value>,
+    pub interface: String, // The interface the invocation came from
+    pub nonce: Value,
+    pub context: Value,
+}
+
+/// Sent to an interface with the answer to a command the core handles itself
+#[derive(serde::Serialize)]
+pub struct ReplyPacket {
+    #[serde(rename = "type")]
+    pub type_: String,
+    pub event: String,
+    pub data: ReplyData,
+}
+
+#[derive(serde::Serialize)]
+pub struct ReplyData {
+    pub message: String,
+    pub nonce: Value,
+    pub context: Value,
+}
+
+/// Sent back to an interface when a command is invoked too often
+#[derive(serde::Serialize)]
+pub struct CooldownPacket {
+    #[serde(rename = "type")]
+    pub type_: String,
+    pub name: String,
+    pub nonce: Value,
+    pub retry_after: f64, // Seconds until the command can be invoked again
+}
+
+/// Sent back to an interface when a user invokes a command they aren't allowed to
+#[derive(serde::Serialize)]
+pub struct PermissionDeniedPacket {
+    #[serde(rename = "type")]
+    pub type_: String,
+    pub name: String,
+    pub nonce: Value,
+    pub permission: String, // The permission the user is missing
+}
+
+/// Sent back to an interface when an invocation can't be routed
+#[derive(serde::Serialize)]
+pub struct InvokeErrorPacket {
+    #[serde(rename = "type")]
+    pub type_: String,
+    pub name: String,
+    pub nonce: Value,
+    pub reason: String,
+}
+
 impl Clone for Command {
     fn clone(&self) -> Self {
         Command {
             structure: self.structure.clone(),
             source: self.source.clone(),
+            name: self.name.clone(),
+            preset: self.preset.clone(),
+        }
+    }
+}
+
+impl Clone for Macro {
+    fn clone(&self) -> Self {
+        Macro {
+            command: self.command.clone(),
+            options: self.options.clone(),
+            description: self.description.clone(),
         }
     }
 }
@@ -56,6 +257,9 @@ impl Clone for CommandStructure {
             plugin: self.plugin.clone(),
             description: self.description.clone(),
             options: self.options.clone(),
+            rate_limit: self.rate_limit.clone(),
+            permission: self.permission.clone(),
+            alias: self.alias.clone(),
         }
     }
 }
@@ -64,6 +268,7 @@ impl Clone for Option {
     fn clone(&self) -> Self {
         Option {
             name: self.name.clone(),
+            type_: self.type_.clone(),
             description: self.description.clone(),
             required: self.required,
             choices: self.choices.clone(),
@@ -71,6 +276,16 @@ impl Clone for Option {
     }
 }
 
+impl Clone for RateLimit {
+    fn clone(&self) -> Self {
+        RateLimit {
+            count: self.count,
+            window: self.window,
+            scope: self.scope,
+        }
+    }
+}
+
 impl Clone for Choice {
     fn clone(&self) -> Self {
         Choice {
@@ -80,40 +295,745 @@ impl Clone for Choice {
     }
 }
 
+impl CommandRegistry {
+    pub fn new(config: &Config) -> CommandRegistry {
+        let mut registry = CommandRegistry {
+            sources: BTreeMap::new(),
+            order: vec![],
+            sent: HashMap::new(),
+            acknowledged: HashMap::new(),
+            conflicts: config.command_conflicts,
+            overrides: config.command_overrides.clone(),
+            prefix: config.command_prefix.clone(),
+            rate_limits: config.rate_limits.clone(),
+            invocations: HashMap::new(),
+            swept: Instant::now(),
+            permissions: config.permissions.clone(),
+            aliases: config.aliases.clone().into_iter().collect(),
+            macros: config.macros.clone().into_iter().collect(),
+            left_out: BTreeMap::new(),
+        };
+        // The core registers first, so its commands win conflicts unless the config says otherwise
+        registry.replace("core", vec![help_structure()]);
+        registry
+    }
+
+    /// Replaces all the commands of a source with a new set
+    /// # Arguments
+    /// * `source` - The component that registered the commands
+    /// * `structures` - The commands it registered, a later command replaces an earlier one with the same name
+    /// # Returns
+    /// * The conflicts the new commands are part of
+    pub fn replace(&mut self, source: &str, structures: Vec<CommandStructure>) -> Vec<Conflict> {
+        let mut commands = BTreeMap::new();
+        for mut structure in structures {
+            // Only the core makes aliases
+            structure.alias = None;
+            commands.insert(structure.name.clone(), structure);
+        }
+        let names: Vec<String> = commands.keys().cloned().collect();
+        self.sources.insert(source.to_string(), commands);
+        if !self.order.iter().any(|s| s == source) {
+            self.order.push(source.to_string());
+        }
+
+        let mut conflicts = vec![];
+        for name in names {
+            if self.owners(&name).len() > 1 {
+                conflicts.push(self.resolve(&name));
+            }
+        }
+        conflicts
+    }
+
+    /// Removes all the commands of a source
+    pub fn remove(&mut self, source: &str) {
+        self.sources.remove(source);
+        self.order.retain(|s| s != source);
+    }
+
+    /// Moves the commands of a source to its new ID
+    pub fn rename(&mut self, old: &str, new: &str) {
+        if let Some(mut commands) = self.sources.remove(old) {
+            for structure in commands.values_mut() {
+                if structure.plugin == old {
+                    structure.plugin = new.to_string();
+                }
+            }
+            self.sources.insert(new.to_string(), commands);
+        }
+        for source in self.order.iter_mut() {
+            if source == old {
+                *source = new.to_string();
+            }
+        }
+        if let Some(sent) = self.sent.remove(old) {
+            self.sent.insert(new.to_string(), sent);
+        }
+        if let Some(acknowledged) = self.acknowledged.remove(old) {
+            self.acknowledged.insert(new.to_string(), acknowledged);
+        }
+    }
+
+    /// Lists every registered command along with its source, with conflicts resolved
+    /// Aliases and macros come last, pointing at the commands they run
+    pub fn commands(&self) -> Vec<Command> {
+        let mut commands = self.registered();
+        let mut shortcuts = self
+            .shortcuts(&commands)
+            .into_iter()
+            .filter_map(|(_, command)| command.ok())
+            .collect();
+        commands.append(&mut shortcuts);
+        commands
+    }
+
+    /// Gets the commands the components registered, after conflicts are resolved
+    fn registered(&self) -> Vec<Command> {
+        let mut commands = vec![];
+        for (source, structures) in self.sources.iter() {
+            for (name, structure) in structures.iter() {
+                let mut structure = structure.clone();
+                if self.owners(name).len() > 1 {
+                    let conflict = self.resolve(name);
+                    if conflict.winner.as_deref() != Some(source.as_str()) {
+                        match self.conflicts {
+                            ConflictPolicy::FirstWins => continue,
+                            ConflictPolicy::Namespace => {
+                                // The plugin field is whatever the plugin said, the source is who it really is
+                                structure.name = format!("{}:{}", source, name);
+                            }
+                        }
+                    }
+                }
+                commands.push(Command {
+                    structure,
+                    source: source.clone(),
+                    name: name.clone(),
+                    preset: Map::new(),
+                });
+            }
+        }
+        commands
+    }
+
+    /// Makes the aliases and macros out of the registered commands
+    /// # Returns
+    /// * What each alias and macro is called in the log, and its command or why it was left out
+    fn shortcuts(&self, commands: &[Command]) -> Vec<(String, Result<Command, String>)> {
+        let mut shortcuts = vec![];
+        for (alias, target) in self.aliases.iter() {
+            shortcuts.push((
+                format!("Alias {}", alias),
+                shortcut(commands, alias, target, &Map::new(), None),
+            ));
+        }
+        for (name, macro_) in self.macros.iter() {
+            let description = macro_.description.as_deref();
+            shortcuts.push((
+                format!("Macro {}", name),
+                shortcut(
+                    commands,
+                    name,
+                    &macro_.command,
+                    &macro_.options,
+                    description,
+                ),
+            ));
+        }
+        shortcuts
+    }
+
+    /// Finds the aliases and macros that are left out, so that an admin knows why one went missing
+    /// Each one is only reported once, until it is back or left out for another reason
+    /// # Returns
+    /// * A line for the log for each one that wasn't reported yet
+    pub fn left_out(&mut self) -> Vec<String> {
+        let mut left_out = BTreeMap::new();
+        for (name, command) in self.shortcuts(&self.registered()) {
+            if let Err(reason) = command {
+                left_out.insert(name, reason);
+            }
+        }
+        let reports = left_out
+            .iter()
+            .filter(|(name, reason)| self.left_out.get(*name) != Some(*reason))
+            .map(|(name, reason)| format!("{} is left out, {}", name, reason))
+            .collect();
+        self.left_out = left_out;
+        reports
+    }
+
+    /// Gets the commands an interface needs to hear about, and remembers that it has been sent them
+    /// # Arguments
+    /// * `interface` - The interface to send the commands to
+    /// * `diffs` - Whether the interface understands diffs, otherwise it gets the whole list whenever something changed
+    /// # Returns
+    /// * The whole list if the interface has never been sent one, a diff if it has, or None if nothing changed
+    pub fn update_for(&mut self, interface: &str, diffs: bool) -> std::option::Option<String> {
+        let mut current = BTreeMap::new();
+        for command in self.commands() {
+            current.insert(command.structure.name.clone(), command.structure);
+        }
+        let last = match self.sent.insert(interface.to_string(), current.clone()) {
+            Some(last) => last,
+            None => return Some(create_packet(self.commands())),
+        };
+        if !diffs {
+            if last == current {
+                return None;
+            }
+            return Some(create_packet(self.commands()));
+        }
+
+        let mut diff = CommandDiffPacket {
+            type_: "commands_diff".to_string(),
+            added: vec![],
+            removed: vec![],
+            changed: vec![],
+        };
+        for (name, structure) in current.iter() {
+            match last.get(name) {
+                Some(old) if old == structure => {}
+                Some(_) => diff.changed.push(structure.clone()),
+                None => diff.added.push(structure.clone()),
+            }
+        }
+        for name in last.keys() {
+            if !current.contains_key(name) {
+                diff.removed.push(name.clone());
+            }
+        }
+        if diff.added.is_empty() && diff.removed.is_empty() && diff.changed.is_empty() {
+            return None;
+        }
+        serde_json::to_string(&diff).ok()
+    }
+
+    /// Forgets what an interface was sent, so that it gets the whole list next time
+    pub fn forget(&mut self, interface: &str) {
+        self.sent.remove(interface);
+    }
+
+    /// Remembers that an interface has registered what it was last sent
+    /// This is what gets cached, so that it only gets a diff after a restart
+    pub fn acknowledge(&mut self, interface: &str) {
+        if let Some(sent) = self.sent.get(interface) {
+            self.acknowledged
+                .insert(interface.to_string(), sent.clone());
+        }
+    }
+
+    /// Gets the sources that registered a command name, in the order they registered
+    fn owners(&self, name: &str) -> Vec<String> {
+        let mut owners = vec![];
+        for source in self.order.iter() {
+            match self.sources.get(source) {
+                Some(commands) if commands.contains_key(name) => owners.push(source.clone()),
+                _ => {}
+            }
+        }
+        owners
+    }
+
+    /// Decides who keeps a command name that more than one source registered
+    fn resolve(&self, name: &str) -> Conflict {
+        let mut losers = self.owners(name);
+        let winner = match self.overrides.get(name) {
+            // The config has the final say, as long as the source it names registered the command
+            Some(source) if losers.contains(source) => Some(source.clone()),
+            _ => match self.conflicts {
+                ConflictPolicy::FirstWins => Some(losers[0].clone()),
+                ConflictPolicy::Namespace => None,
+            },
+        };
+        if let Some(winner) = &winner {
+            losers.retain(|s| s != winner);
+        }
+        Conflict {
+            name: name.to_string(),
+            winner,
+            losers,
+        }
+    }
+
+    /// Finds a command by the name interfaces know it by
+    pub fn find(&self, name: &str) -> std::option::Option<Command> {
+        self.commands()
+            .into_iter()
+            .find(|c| c.structure.name == name)
+    }
+
+    /// Checks that whoever invoked a command is allowed to
+    /// # Returns
+    /// * The permission they are missing, if they are
+    pub fn permitted(
+        &self,
+        command: &Command,
+        interface: &str,
+        context: &Value,
+    ) -> Result<(), String> {
+        // The config gets the final say, by the name the command was registered under so that namespacing can't get around it
+        let permission = match self
+            .permissions
+            .commands
+            .get(&command.name)
+            .or(command.structure.permission.as_ref())
+        {
+            Some(permission) => permission,
+            None => return Ok(()),
+        };
+        if self
+            .permissions
+            .allowed(interface, context["user"].as_str(), permission)
+        {
+            Ok(())
+        } else {
+            Err(permission.clone())
+        }
+    }
+
+    /// Checks an invocation against the rate limit of its command, without counting it
+    /// # Arguments
+    /// * `command` - The command being invoked
+    /// * `interface` - The interface it was invoked through
+    /// * `context` - Where it was invoked from, with the user and channel if the interface knows them
+    /// # Returns
+    /// * How long until the command can be invoked again, if it can't be now
+    pub fn rate_limit(
+        &mut self,
+        command: &Command,
+        interface: &str,
+        context: &Value,
+    ) -> Result<(), Duration> {
+        let (bucket, window, count) = match self.bucket(command, interface, context) {
+            Some(bucket) => bucket,
+            None => return Ok(()),
+        };
+
+        let now = Instant::now();
+        // Every user that ever invoked a command would have a bucket otherwise
+        if now.duration_since(self.swept) >= SWEEP_INTERVAL {
+            self.invocations.retain(|_, (window, invocations)| {
+                invocations
+                    .back()
+                    .is_some_and(|newest| now.duration_since(*newest) < *window)
+            });
+            self.swept = now;
+        }
+        let mut empty = VecDeque::new();
+        let invocations = match self.invocations.get_mut(&bucket) {
+            Some((_, invocations)) => invocations,
+            None => &mut empty,
+        };
+        while let Some(oldest) = invocations.front() {
+            if now.duration_since(*oldest) >= window {
+                invocations.pop_front();
+            } else {
+                break;
+            }
+        }
+        if invocations.len() >= count as usize {
+            return match invocations.front() {
+                Some(oldest) => Err(window - now.duration_since(*oldest)),
+                None => Err(window), // Zero invocations allowed
+            };
+        }
+        Ok(())
+    }
+
+    /// Counts an invocation against the rate limit of its command, once it has made it to its plugin
+    /// # Arguments
+    /// * `command` - The command that was invoked
+    /// * `interface` - The interface it was invoked through
+    /// * `context` - Where it was invoked from, with the user and channel if the interface knows them
+    pub fn record(&mut self, command: &Command, interface: &str, context: &Value) {
+        let (bucket, window, _) = match self.bucket(command, interface, context) {
+            Some(bucket) => bucket,
+            None => return,
+        };
+        let (bucket_window, invocations) = self
+            .invocations
+            .entry(bucket)
+            .or_insert_with(|| (window, VecDeque::new()));
+        *bucket_window = window;
+        invocations.push_back(Instant::now());
+    }
+
+    /// Works out which rate limit bucket an invocation is counted in
+    /// # Returns
+    /// * The bucket, its window and how many invocations fit in it, None if the command isn't rate limited
+    fn bucket(
+        &self,
+        command: &Command,
+        interface: &str,
+        context: &Value,
+    ) -> std::option::Option<(String, Duration, u32)> {
+        // The config gets the final say, by the name the command was registered under so that namespacing can't get around it
+        let limit = self
+            .rate_limits
+            .get(&command.name)
+            .or(command.structure.rate_limit.as_ref())?;
+        // Interfaces that don't say who invoked it put all of those invocations in one bucket
+        let who = match limit.scope {
+            RateLimitScope::User => context["user"].to_string(),
+            RateLimitScope::Channel => context["channel"].to_string(),
+            RateLimitScope::Global => "".to_string(),
+        };
+        Some((
+            format!("{}:{}/{}/{}", command.source, command.name, interface, who),
+            Duration::from_secs(limit.window),
+            limit.count,
+        ))
+    }
+
+    /// Gets what a source's command ends up being called after conflicts, None if it was dropped
+    pub fn resolved_name(&self, source: &str, name: &str) -> std::option::Option<String> {
+        self.commands()
+            .into_iter()
+            .find(|c| c.source == source && c.name == name)
+            .map(|c| c.structure.name)
+    }
+}
+
 /// Saves the current commands to a file for restart caching.
 /// When the bot is restarted, the command cache will be used until components update their own plugins.
 /// This is to prevent interfaces removing commands only to immediately replace them.
 /// Example: https://discord.com/developers/docs/interactions/application-commands#registering-a-command
 /// "There is a global rate limit of 200 application command creates per day, per guild"
-pub async fn save_cache(command_structures: Vec<Command>) {
+/// # Returns
+/// * Why the cache couldn't be written, if it couldn't
+pub async fn save_cache(registry: &CommandRegistry) -> Result<(), String> {
+    let path = cache_path();
     // Create cache folder if it doesn't exist
+    if let Some(folder) = path.parent() {
+        tokio::fs::create_dir_all(folder)
+            .await
+            .map_err(|e| format!("failed to create {}: {}", folder.display(), e))?;
+    }
+
+    let mut cache = CommandCache {
+        version: CACHE_VERSION,
+        order: vec![],
+        sources: BTreeMap::new(),
+        interfaces: BTreeMap::new(),
+    };
+    for source in registry.order.iter() {
+        // The core registers its own commands every time it starts
+        if source == "core" {
+            continue;
+        }
+        if let Some(commands) = registry.sources.get(source) {
+            cache.order.push(source.clone());
+            cache
+                .sources
+                .insert(source.clone(), commands.values().cloned().collect());
+        }
+    }
+    for (interface, commands) in registry.acknowledged.iter() {
+        cache
+            .interfaces
+            .insert(interface.clone(), commands.values().cloned().collect());
+    }
+    let json = serde_json::to_string(&cache).map_err(|e| e.to_string())?;
+
+    // Written next to the cache and moved over it, so that a crash halfway through can't corrupt it
+    let temp = path.with_extension("json.tmp");
+    tokio::fs::write(&temp, json)
+        .await
+        .map_err(|e| format!("failed to write {}: {}", temp.display(), e))?;
+    tokio::fs::rename(&temp, &path)
+        .await
+        .map_err(|e| format!("failed to write {}: {}", path.display(), e))
+}
+
+/// Loads the commands saved by save_cache
+/// # Returns
+/// * The registry with the cached commands, None if there is no cache, or why the cache couldn't be read
+pub async fn load_cache(config: &Config) -> Result<std::option::Option<CommandRegistry>, String> {
+    let path = cache_path();
+    let contents = match tokio::fs::read_to_string(&path).await {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(format!("failed to read {}: {}", path.display(), e)),
+    };
+    let cache = serde_json::from_str(&contents)
+        .map_err(|e| e.to_string())
+        .and_then(migrate_cache)
+        .map_err(|e| format!("{} is corrupt: {}", path.display(), e))?;
+
+    let mut registry = CommandRegistry::new(config);
+    let mut sources = cache.sources;
+    for source in cache.order {
+        if let Some(structures) = sources.remove(&source) {
+            registry.replace(&source, structures);
+        }
+    }
+    for (source, structures) in sources {
+        registry.replace(&source, structures);
+    }
+    for (interface, structures) in cache.interfaces {
+        let mut commands = BTreeMap::new();
+        for structure in structures {
+            commands.insert(structure.name.clone(), structure);
+        }
+        // The interface already has these, so it only needs a diff when it comes back
+        registry.sent.insert(interface.clone(), commands.clone());
+        registry.acknowledged.insert(interface, commands);
+    }
+    Ok(Some(registry))
+}
+
+/// Where the command cache lives, in RUST_BOT_CACHE_PATH if it is set
+fn cache_path() -> PathBuf {
     let cache_path = std::env::var("RUST_BOT_CACHE_PATH").unwrap_or_else(|_| "cache".to_string());
-    let cache_path = std::path::Path::new(&cache_path);
-    if !cache_path.exists() {
-        std::fs::create_dir_all(cache_path).unwrap();
+    Path::new(&cache_path).join("commands.json")
+}
+
+/// Upgrades a cache written by an older version of the core
+fn migrate_cache(value: Value) -> Result<CommandCache, String> {
+    // Version 1 was a list of every command, named the way interfaces saw them
+    if value.is_array() {
+        let structures: Vec<CommandStructure> =
+            serde_json::from_value(value).map_err(|e| e.to_string())?;
+        let mut cache = CommandCache {
+            version: CACHE_VERSION,
+            order: vec![],
+            sources: BTreeMap::new(),
+            interfaces: BTreeMap::new(),
+        };
+        for mut structure in structures {
+            if structure.plugin == "core" {
+                continue;
+            }
+            // Version 1 didn't keep sources, but the core filled in the plugin field with the source
+            let source = structure.plugin.clone();
+            // Undo namespacing, it gets worked out again when the commands are registered
+            let namespace = format!("{}:", source);
+            if let Some(name) = structure.name.strip_prefix(namespace.as_str()) {
+                structure.name = name.to_string();
+            }
+            if !cache.order.contains(&source) {
+                cache.order.push(source.clone());
+            }
+            cache.sources.entry(source).or_default().push(structure);
+        }
+        return Ok(cache);
     }
-    let mut commands = vec![];
-    for i in command_structures {
-        commands.push(i.structure.clone());
+
+    let version = match value["version"].as_u64() {
+        Some(version) => version,
+        None => return Err("missing version".to_string()),
+    };
+    if version > CACHE_VERSION as u64 {
+        return Err(format!(
+            "version {} is newer than this core understands ({})",
+            version, CACHE_VERSION
+        ));
+    }
+    serde_json::from_value(value).map_err(|e| e.to_string())
+}
+
+/// Checks the options of an invocation against the options the command was registered with
+/// # Returns
+/// * Why the options are invalid, if they are
+pub fn validate(structure: &CommandStructure, options: &Map<String, Value>) -> Result<(), String> {
+    for name in options.keys() {
+        if !structure.options.iter().any(|o| &o.name == name) {
+            return Err(format!("unknown option {}", name));
+        }
+    }
+    for option in structure.options.iter() {
+        let value = match options.get(&option.name) {
+            Some(value) => value,
+            None => {
+                if option.required {
+                    return Err(format!("missing required option {}", option.name));
+                }
+                continue;
+            }
+        };
+        let type_matches = match option.type_.as_str() {
+            "string" => value.is_string(),
+            "integer" => value.is_i64() || value.is_u64(),
+            "number" => value.is_number(),
+            "bool" | "boolean" => value.is_boolean(),
+            _ => true, // Untyped, anything goes
+        };
+        if !type_matches {
+            return Err(format!("option {} must be a {}", option.name, option.type_));
+        }
+        if !option.choices.is_empty() && !option.choices.iter().any(|c| &c.value == value) {
+            let choices: Vec<String> = option.choices.iter().map(|c| c.name.clone()).collect();
+            return Err(format!(
+                "option {} must be one of {}",
+                option.name,
+                choices.join(", ")
+            ));
+        }
+    }
+    Ok(())
+}
+
+impl From<String> for InvokeError {
+    fn from(reason: String) -> Self {
+        InvokeError::Invalid(reason)
+    }
+}
+
+impl InvokeError {
+    /// Creates the packet telling the interface what went wrong
+    pub fn packet(self, name: String, nonce: Value) -> String {
+        let to_send = match self {
+            InvokeError::Invalid(reason) => serde_json::to_string(&InvokeErrorPacket {
+                type_: "invoke_error".to_string(),
+                name,
+                nonce,
+                reason,
+            }),
+            InvokeError::Cooldown(retry_after) => serde_json::to_string(&CooldownPacket {
+                type_: "cooldown".to_string(),
+                name,
+                nonce,
+                retry_after: retry_after.as_secs_f64(),
+            }),
+            InvokeError::Denied(permission) => serde_json::to_string(&PermissionDeniedPacket {
+                type_: "permission_denied".to_string(),
+                name,
+                nonce,
+                permission,
+            }),
+        };
+        to_send.unwrap_or_default()
+    }
+}
+
+/// The help command, which the core answers itself
+fn help_structure() -> CommandStructure {
+    CommandStructure {
+        name: "help".to_string(),
+        plugin: "core".to_string(),
+        description: "Shows how to use a command, or lists every command".to_string(),
+        options: vec![Option {
+            name: "command".to_string(),
+            type_: "string".to_string(),
+            description: "The command to show".to_string(),
+            required: false,
+            choices: vec![],
+        }],
+        rate_limit: None,
+        permission: None,
+        alias: None,
     }
-    let mut file = File::create("cache/commands.json").await.unwrap();
-    let json = serde_json::to_string(&commands).unwrap();
-    file.write_all(json.as_bytes()).await.unwrap();
 }
 
-pub async fn load_cache() -> Vec<Command> {
-    let mut file = File::open("cache/commands.json").await.unwrap();
-    let mut contents = String::new();
-    file.read_to_string(&mut contents).await.unwrap();
-    let command_structures: Vec<CommandStructure> = serde_json::from_str(&contents).unwrap();
-    let mut commands = vec![];
-    for i in command_structures {
-        commands.push(Command {
-            structure: i,
-            source: "cache".to_string(),
-        });
+/// Makes an alias or macro out of the command it runs
+/// # Arguments
+/// * `commands` - The registered commands, aliases of aliases aren't allowed
+/// * `name` - The name of the alias or macro
+/// * `target` - The command it runs
+/// * `preset` - The options a macro fills in
+/// * `description` - Replaces the description of the command
+/// # Returns
+/// * The alias or macro, or why it can't be made
+fn shortcut(
+    commands: &[Command],
+    name: &str,
+    target: &str,
+    preset: &Map<String, Value>,
+    description: std::option::Option<&str>,
+) -> Result<Command, String> {
+    if let Some(command) = commands.iter().find(|c| c.structure.name == name) {
+        return Err(format!(
+            "{} registered a command with the same name",
+            command.source
+        ));
     }
-    commands
+    let command = match commands.iter().find(|c| c.structure.name == target) {
+        Some(command) => command,
+        None => return Err(format!("no command called {} is registered", target)),
+    };
+    let mut structure = command.structure.clone();
+    structure.name = name.to_string();
+    structure.alias = Some(target.to_string());
+    structure.options.retain(|o| !preset.contains_key(&o.name));
+    if let Some(description) = description {
+        structure.description = description.to_string();
+    }
+    Ok(Command {
+        structure,
+        source: command.source.clone(),
+        name: command.name.clone(),
+        preset: preset.clone(),
+    })
+}
+
+/// Writes out how to use a command, such as "!roll <dice:integer> [text] - Rolls some dice"
+fn usage(structure: &CommandStructure, prefix: &str) -> String {
+    let mut line = format!("{}{}", prefix, structure.name);
+    for option in structure.options.iter() {
+        let mut option_name = option.name.clone();
+        if !option.type_.is_empty() {
+            option_name = format!("{}:{}", option_name, option.type_);
+        }
+        if option.required {
+            line.push_str(&format!(" <{}>", option_name));
+        } else {
+            line.push_str(&format!(" [{}]", option_name));
+        }
+    }
+    match &structure.alias {
+        Some(alias) => format!(
+            "{} - {} (runs {}{})",
+            line, structure.description, prefix, alias
+        ),
+        None => format!("{} - {}", line, structure.description),
+    }
+}
+
+/// Generates help text for one command, or for every command grouped by plugin
+/// # Arguments
+/// * `registry` - The registered commands
+/// * `name` - The command to show, None for all of them
+/// # Returns
+/// * The help text, or an error if the command doesn't exist
+pub fn help_text(
+    registry: &CommandRegistry,
+    name: std::option::Option<&str>,
+) -> Result<String, String> {
+    if let Some(name) = name {
+        let name = name.strip_prefix(registry.prefix.as_str()).unwrap_or(name);
+        let command = match registry.find(name) {
+            Some(command) => command,
+            None => return Err(format!("unknown command {}", name)),
+        };
+        let mut lines = vec![usage(&command.structure, &registry.prefix)];
+        for option in command.structure.options.iter() {
+            let mut line = format!("  {} - {}", option.name, option.description);
+            if !option.choices.is_empty() {
+                let choices: Vec<String> = option.choices.iter().map(|c| c.name.clone()).collect();
+                line.push_str(&format!(" (one of {})", choices.join(", ")));
+            }
+            lines.push(line);
+        }
+        return Ok(lines.join("\n"));
+    }
+
+    let mut plugins: BTreeMap<String, Vec<String>> = BTreeMap::new();
+    for command in registry.commands() {
+        plugins
+            .entry(command.structure.plugin.clone())
+            .or_default()
+            .push(usage(&command.structure, &registry.prefix));
+    }
+    let mut lines = vec![];
+    for (plugin, usages) in plugins {
+        lines.push(format!("{}:", plugin));
+        for usage in usages {
+            lines.push(format!("  {}", usage));
+        }
+    }
+    Ok(lines.join("\n"))
 }
 
 pub fn create_packet(commands: Vec<Command>) -> String {
@@ -121,10 +1041,396 @@ pub fn create_packet(commands: Vec<Command>) -> String {
     for i in commands.iter() {
         cmds.push(i.structure.clone());
     }
-    let json = serde_json::to_string(&CommandPacket {
+    serde_json::to_string(&CommandPacket {
         type_: "commands".to_string(),
         commands: cmds,
     })
-    .unwrap();
-    json
+    .unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn structure(name: &str) -> CommandStructure {
+        CommandStructure {
+            name: name.to_string(),
+            plugin: "".to_string(),
+            description: format!("Does {}", name),
+            options: vec![],
+            rate_limit: None,
+            permission: None,
+            alias: None,
+        }
+    }
+
+    fn registry(config: Config) -> CommandRegistry {
+        let mut registry = CommandRegistry::new(&config);
+        registry.replace("a", vec![structure("ban"), structure("roll")]);
+        registry.replace("b", vec![structure("ban")]);
+        registry
+    }
+
+    fn names(registry: &CommandRegistry) -> Vec<String> {
+        registry
+            .commands()
+            .into_iter()
+            .map(|command| command.structure.name)
+            .collect()
+    }
+
+    #[test]
+    fn first_registration_wins() {
+        let mut config = Config::new();
+        config.command_conflicts = ConflictPolicy::FirstWins;
+        let mut registry = CommandRegistry::new(&config);
+        assert!(registry.replace("a", vec![structure("ban")]).is_empty());
+        let conflicts = registry.replace("b", vec![structure("ban")]);
+        assert_eq!(conflicts.len(), 1);
+        assert_eq!(conflicts[0].name, "ban");
+        assert_eq!(conflicts[0].winner.as_deref(), Some("a"));
+        assert_eq!(conflicts[0].losers, ["b"]);
+        assert_eq!(names(&registry), ["ban", "help"]);
+        assert_eq!(registry.find("ban").unwrap().source, "a");
+        assert_eq!(registry.resolved_name("b", "ban"), None);
+        // The loser gets it once the winner is gone
+        registry.remove("a");
+        assert_eq!(registry.find("ban").unwrap().source, "b");
+    }
+
+    #[test]
+    fn overrides_pick_the_winner() {
+        let mut config = Config::new();
+        config
+            .command_overrides
+            .insert("ban".to_string(), "b".to_string());
+        config
+            .command_overrides
+            .insert("roll".to_string(), "nobody".to_string());
+        let mut registry = registry(config);
+        registry.replace("b", vec![structure("ban"), structure("roll")]);
+        assert_eq!(registry.find("ban").unwrap().source, "b");
+        // An override for a source that didn't register the command is ignored
+        assert_eq!(registry.find("roll").unwrap().source, "a");
+    }
+
+    #[test]
+    fn namespacing_renames_every_conflict() {
+        let mut config = Config::new();
+        config.command_conflicts = ConflictPolicy::Namespace;
+        let registry = registry(config);
+        assert_eq!(names(&registry), ["a:ban", "roll", "b:ban", "help"]);
+        let command = registry.find("b:ban").unwrap();
+        assert_eq!(command.source, "b");
+        assert_eq!(command.name, "ban");
+        assert!(registry.find("ban").is_none());
+        assert_eq!(registry.resolved_name("a", "ban").as_deref(), Some("a:ban"));
+    }
+
+    #[test]
+    fn left_out_shortcuts_are_reported_once() {
+        let mut config = Config::new();
+        config.aliases.insert("r".to_string(), "roll".to_string());
+        config.aliases.insert("ban".to_string(), "roll".to_string());
+        config.macros.insert(
+            "d20".to_string(),
+            Macro {
+                command: "dice".to_string(),
+                options: Map::new(),
+                description: None,
+            },
+        );
+        let mut registry = registry(config);
+        assert_eq!(
+            registry.left_out(),
+            [
+                "Alias ban is left out, a registered a command with the same name",
+                "Macro d20 is left out, no command called dice is registered",
+            ]
+        );
+        assert!(registry.find("r").is_some());
+        assert!(registry.left_out().is_empty());
+        // Losing its command is reported, and so is getting it back and losing it again
+        registry.remove("a");
+        assert_eq!(
+            registry.left_out(),
+            [
+                "Alias ban is left out, b registered a command with the same name",
+                "Alias r is left out, no command called roll is registered",
+            ]
+        );
+        registry.replace("a", vec![structure("roll")]);
+        assert!(registry.left_out().is_empty());
+        registry.remove("a");
+        assert_eq!(
+            registry.left_out(),
+            ["Alias r is left out, no command called roll is registered"]
+        );
+    }
+
+    #[test]
+    fn core_commands_win_by_default() {
+        let mut registry = CommandRegistry::new(&Config::new());
+        registry.replace("a", vec![structure("help")]);
+        assert_eq!(registry.find("help").unwrap().source, "core");
+    }
+
+    fn update(registry: &mut CommandRegistry, diffs: bool) -> std::option::Option<Value> {
+        registry
+            .update_for("chat", diffs)
+            .map(|packet| serde_json::from_str(&packet).unwrap())
+    }
+
+    #[test]
+    fn diffs_after_the_first_list() {
+        let mut registry = registry(Config::new());
+        let first = update(&mut registry, true).unwrap();
+        assert_eq!(first["type"], "commands");
+        assert_eq!(first["commands"].as_array().unwrap().len(), 3);
+        // Nothing changed
+        assert!(update(&mut registry, true).is_none());
+
+        let mut roll = structure("roll");
+        roll.description = "Rolls better".to_string();
+        registry.replace("a", vec![roll, structure("flip")]);
+        let diff = update(&mut registry, true).unwrap();
+        assert_eq!(diff["type"], "commands_diff");
+        assert_eq!(diff["added"][0]["name"], "flip");
+        assert_eq!(diff["changed"][0]["description"], "Rolls better");
+        // b keeps ban now that a dropped it, which looks the same to interfaces
+        assert_eq!(diff["removed"], serde_json::json!([]));
+        assert!(update(&mut registry, true).is_none());
+
+        registry.remove("b");
+        let diff = update(&mut registry, true).unwrap();
+        assert_eq!(diff["removed"], serde_json::json!(["ban"]));
+        assert!(diff["added"].as_array().unwrap().is_empty());
+    }
+
+    #[test]
+    fn whole_list_without_diffs() {
+        let mut registry = registry(Config::new());
+        assert_eq!(update(&mut registry, false).unwrap()["type"], "commands");
+        assert!(update(&mut registry, false).is_none());
+        registry.remove("b");
+        // b never got to keep ban, so the list interfaces see is the same
+        assert!(update(&mut registry, false).is_none());
+        registry.remove("a");
+        let list = update(&mut registry, false).unwrap();
+        assert_eq!(list["type"], "commands");
+        assert_eq!(list["commands"].as_array().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn forgotten_interfaces_get_the_whole_list() {
+        let mut registry = registry(Config::new());
+        update(&mut registry, true);
+        registry.forget("chat");
+        assert_eq!(update(&mut registry, true).unwrap()["type"], "commands");
+    }
+
+    #[test]
+    fn permission_overrides_cover_namespaced_commands() {
+        let mut config = Config::new();
+        config.command_conflicts = ConflictPolicy::Namespace;
+        config
+            .permissions
+            .commands
+            .insert("ban".to_string(), "moderate".to_string());
+        config
+            .aliases
+            .insert("kick".to_string(), "b:ban".to_string());
+        let registry = registry(config);
+        let context = serde_json::json!({ "user": "someone" });
+        for name in ["a:ban", "b:ban", "kick"] {
+            let command = registry.find(name).unwrap();
+            assert_eq!(
+                registry.permitted(&command, "chat", &context),
+                Err("moderate".to_string()),
+                "{}",
+                name
+            );
+        }
+        let roll = registry.find("roll").unwrap();
+        assert_eq!(registry.permitted(&roll, "chat", &context), Ok(()));
+    }
+
+    #[test]
+    fn rate_limits_cover_namespaced_commands() {
+        let mut config = Config::new();
+        config.command_conflicts = ConflictPolicy::Namespace;
+        config.rate_limits.insert(
+            "ban".to_string(),
+            RateLimit {
+                count: 1,
+                window: 60,
+                scope: RateLimitScope::User,
+            },
+        );
+        let mut registry = registry(config);
+        let context = serde_json::json!({ "user": "someone" });
+        let command = registry.find("b:ban").unwrap();
+        assert!(registry.rate_limit(&command, "chat", &context).is_ok());
+        registry.record(&command, "chat", &context);
+        assert!(registry.rate_limit(&command, "chat", &context).is_err());
+    }
+
+    fn limited(scope: RateLimitScope) -> CommandRegistry {
+        let mut config = Config::new();
+        config.rate_limits.insert(
+            "roll".to_string(),
+            RateLimit {
+                count: 1,
+                window: 60,
+                scope,
+            },
+        );
+        registry(config)
+    }
+
+    #[test]
+    fn buckets_follow_the_scope() {
+        let alice = serde_json::json!({ "user": "alice", "channel": "general" });
+        let bob = serde_json::json!({ "user": "bob", "channel": "general" });
+        let elsewhere = serde_json::json!({ "user": "bob", "channel": "random" });
+        for (scope, bob_limited, elsewhere_limited) in [
+            (RateLimitScope::User, false, false),
+            (RateLimitScope::Channel, true, false),
+            (RateLimitScope::Global, true, true),
+        ] {
+            let mut registry = limited(scope);
+            let command = registry.find("roll").unwrap();
+            registry.record(&command, "chat", &alice);
+            assert!(registry.rate_limit(&command, "chat", &alice).is_err());
+            assert_eq!(
+                registry.rate_limit(&command, "chat", &bob).is_err(),
+                bob_limited
+            );
+            assert_eq!(
+                registry.rate_limit(&command, "chat", &elsewhere).is_err(),
+                elsewhere_limited
+            );
+        }
+    }
+
+    #[test]
+    fn invocations_expire_with_the_window() {
+        let mut registry = limited(RateLimitScope::Global);
+        let command = registry.find("roll").unwrap();
+        registry.record(&command, "chat", &Value::Null);
+        let retry_after = registry
+            .rate_limit(&command, "chat", &Value::Null)
+            .unwrap_err();
+        assert!(retry_after <= Duration::from_secs(60));
+        // Pretend it was a minute ago
+        for (_, invocations) in registry.invocations.values_mut() {
+            for invocation in invocations.iter_mut() {
+                *invocation -= Duration::from_secs(60);
+            }
+        }
+        assert!(registry.rate_limit(&command, "chat", &Value::Null).is_ok());
+    }
+
+    #[test]
+    fn idle_buckets_are_swept() {
+        let mut registry = limited(RateLimitScope::User);
+        let command = registry.find("roll").unwrap();
+        let old = serde_json::json!({ "user": "old" });
+        let new = serde_json::json!({ "user": "new" });
+        registry.record(&command, "chat", &old);
+        for (_, invocations) in registry.invocations.values_mut() {
+            for invocation in invocations.iter_mut() {
+                *invocation -= Duration::from_secs(60);
+            }
+        }
+        registry.record(&command, "chat", &new);
+        assert_eq!(registry.invocations.len(), 2);
+        // Not time to sweep yet
+        assert!(registry.rate_limit(&command, "chat", &new).is_err());
+        assert_eq!(registry.invocations.len(), 2);
+        registry.swept -= SWEEP_INTERVAL;
+        assert!(registry.rate_limit(&command, "chat", &new).is_err());
+        assert_eq!(registry.invocations.len(), 1);
+        assert!(registry.rate_limit(&command, "chat", &old).is_ok());
+    }
+
+    #[test]
+    fn only_recorded_invocations_count() {
+        let mut config = Config::new();
+        config.rate_limits.insert(
+            "roll".to_string(),
+            RateLimit {
+                count: 1,
+                window: 60,
+                scope: RateLimitScope::Global,
+            },
+        );
+        let mut registry = registry(config);
+        let command = registry.find("roll").unwrap();
+        for _ in 0..3 {
+            assert!(registry.rate_limit(&command, "chat", &Value::Null).is_ok());
+        }
+        registry.record(&command, "chat", &Value::Null);
+        assert!(registry.rate_limit(&command, "chat", &Value::Null).is_err());
+        // Other interfaces have their own allowance
+        assert!(registry.rate_limit(&command, "irc", &Value::Null).is_ok());
+    }
+
+    #[test]
+    fn version_one_caches_are_migrated() {
+        let cache = migrate_cache(serde_json::json!([
+            {"name": "help", "plugin": "core", "description": "Help"},
+            {"name": "b:ban", "plugin": "b", "description": "Bans"},
+            {"name": "a:ban", "plugin": "a", "description": "Bans"},
+            {"name": "roll", "plugin": "b", "description": "Rolls"},
+            {"name": "a:b", "plugin": "c", "description": "Not namespaced by c"},
+        ]))
+        .unwrap();
+        assert_eq!(cache.version, CACHE_VERSION);
+        assert_eq!(cache.order, ["b", "a", "c"]);
+        let names = |source: &str| -> Vec<String> {
+            cache.sources[source]
+                .iter()
+                .map(|structure| structure.name.clone())
+                .collect()
+        };
+        assert_eq!(names("b"), ["ban", "roll"]);
+        assert_eq!(names("a"), ["ban"]);
+        assert_eq!(names("c"), ["a:b"]);
+        assert!(!cache.sources.contains_key("core"));
+        assert!(cache.interfaces.is_empty());
+    }
+
+    #[test]
+    fn unknown_cache_versions_are_refused() {
+        assert!(migrate_cache(serde_json::json!({ "order": [] })).is_err());
+        let newer = serde_json::json!({ "version": CACHE_VERSION + 1, "order": [], "sources": {} });
+        assert!(migrate_cache(newer).is_err());
+        let current = serde_json::json!({ "version": CACHE_VERSION, "order": [], "sources": {} });
+        assert!(migrate_cache(current).is_ok());
+    }
+
+    #[tokio::test]
+    async fn cache_survives_a_restart_and_corruption_is_reported() {
+        // The only test that touches the cache, since the path is set for the whole process
+        let folder = std::env::temp_dir().join(format!("camel_bot_cache_{}", std::process::id()));
+        std::env::set_var("RUST_BOT_CACHE_PATH", &folder);
+        let config = Config::new();
+        assert!(load_cache(&config).await.unwrap().is_none());
+
+        let mut registry = registry(Config::new());
+        registry.update_for("chat", true);
+        registry.acknowledge("chat");
+        save_cache(&registry).await.unwrap();
+        let mut loaded = load_cache(&config).await.unwrap().unwrap();
+        assert_eq!(names(&loaded), names(&registry));
+        assert_eq!(loaded.order, ["core", "a", "b"]);
+        // The interface already has everything
+        assert!(loaded.update_for("chat", true).is_none());
+
+        std::fs::write(cache_path(), "{\"version\": 2, \"order\": [").unwrap();
+        let e = load_cache(&config).await.err().unwrap();
+        assert!(e.contains("is corrupt"), "{}", e);
+        std::fs::remove_dir_all(&folder).unwrap();
+    }
 }
@@ -8,35 +8,67 @@
 // Note: a sniffer should not be used unless needed because it can be very slow
 
 use async_trait::async_trait;
-use serde_json;
-use std::{collections::HashMap, process::Stdio, sync::Arc};
-use tokio::{
-    io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader},
-    net::{
-        tcp::{ReadHalf, WriteHalf},
-        TcpStream,
-    },
-    process::{ChildStdin, ChildStdout},
-    sync::{
-        mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender},
-        Mutex,
-    },
+use std::{
+    collections::HashMap,
+    process::Stdio,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+use tokio::sync::{
+    mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender},
+    Mutex,
 };
 
 use crate::{
-    commands::{self, Command},
-    packet::Packet,
+    commands::{
+        self, CommandRegistry, ConflictPacket, Invocation, InvokeError, InvokePacket, ReplyData,
+        ReplyPacket,
+    },
+    config::{
+        default_fail_open, default_heartbeat_interval, default_missed_heartbeats,
+        default_sniffer_timeout, DeadPolicy,
+    },
+    encoding::{Encoding, Payload},
+    framing::{FramedReader, FramedWriter, Framing, FramingPacket},
+    handshake::{Capabilities, HelloPacket, HelloReplyPacket, HELLO_TIMEOUT},
+    intents::{Intent, IntentErrorPacket},
+    packet::{
+        legacy_type, next_id, DeliveryFailedPacket, Packet, PingPacket, RequestPacket,
+        ResponsePacket, SnifferIntent, SnifferPacket,
+    },
+    parser,
+    subscriptions::SubscriptionIndex,
+    tls::Connection,
     ui,
 };
 
+// How long a request waits for a response if the caller doesn't say
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+// A request this component made that hasn't been answered yet
+struct PendingRequest {
+    target: String,
+    nonce: serde_json::Value,
+    deadline: Instant,
+}
+
 pub struct Component {
     pub id: String,         // An ID that can be referenced by other components
     pub component_type: u8, // 0 - Interface, 1 - Plugin, 2 - Sniffer
-    pub network: bool, // Whether the component communicates over TCP. If false, it communicates over STDIN/STDOUT
+    pub network: bool, // Whether the component connects over TCP or the Unix socket. If false, it communicates over STDIN/STDOUT
     pub key: String,   // The key used to authenticate with the component if over TCP
     pub sender: UnboundedSender<Packet>,
     pub intents: Vec<String>, // The events that the component wants to receive
-    pub gucci: bool,
+    pub sources: Vec<String>, // The components to receive events from, empty for all
+    pub messages: bool,       // Whether the component accepts send packets
+    pub sniffer: SnifferIntent, // What the component wants to sniff, only used by sniffers
+    pub sniffer_timeout: Duration, // How long the component has to return a sniffed packet
+    pub fail_open: bool, // Whether a sniffed packet that times out is passed on instead of dropped
+    pub capabilities: Capabilities, // The protocol version and features agreed on in the hello
+    pub heartbeat_interval: Duration, // How often the component is pinged
+    pub missed_heartbeats: u32, // How many pings can go unanswered before the component is dead
+    pub on_dead: DeadPolicy, // Whether a dead component is reconnected or removed
+    pub gucci: bool,     // Whether the component is connected and answering its pings
 }
 
 impl Component {
@@ -44,7 +76,7 @@ impl Component {
     /// # Arguments
     /// * `id` - The ID of the component
     /// * `type_` - The type of the component: 0 - Interface, 1 - Plugin, 2 - Sniffer
-    /// * `network` - Whether the component communicates over TCP
+    /// * `network` - Whether the component connects over TCP or the Unix socket
     /// * `key` - The key used to authenticate with the component if over TCP
     /// * `sender` - The sender to send packets to the component
     /// * `receiver` - The receiver to receive packets from the component
@@ -53,23 +85,32 @@ impl Component {
         Component {
             id,
             component_type: type_,
-            network: if key == "" { false } else { true },
+            network: !key.is_empty(),
             key,
             sender,
             intents: Vec::new(),
+            sources: Vec::new(),
+            messages: true,
+            sniffer: SnifferIntent::default(),
+            sniffer_timeout: Duration::from_millis(default_sniffer_timeout()),
+            fail_open: default_fail_open(),
+            capabilities: Capabilities::legacy(),
+            heartbeat_interval: Duration::from_millis(default_heartbeat_interval()),
+            missed_heartbeats: default_missed_heartbeats(),
+            on_dead: DeadPolicy::default(),
             gucci: false,
         }
     }
 
+    #[allow(clippy::too_many_arguments)]
     pub async fn connect(
         mut id: String,
         logger: ui::Logger,
         command: String,
         args: Vec<String>,
-        key: String,
         components: Arc<Mutex<HashMap<String, Component>>>,
-        commands: Arc<Mutex<Vec<Command>>>,
-        network_arc: Arc<Mutex<HashMap<String, UnboundedSender<TcpStream>>>>,
+        commands: Arc<Mutex<CommandRegistry>>,
+        network_arc: Arc<Mutex<HashMap<String, UnboundedSender<Connection>>>>,
         receiver: UnboundedReceiver<Packet>,
     ) {
         // Get command information
@@ -92,29 +133,57 @@ impl Component {
                     loop {
                         // Create channel to receive client from listener
                         let (sender, mut cli_rec) = unbounded_channel();
-                        // Add key to network_arc
+                        // Wait by name, so components without a key can be found too
                         let mut network_arc = network_arc.lock().await;
-                        network_arc.insert(key.clone(), sender);
+                        // Whatever we waited under before changing our ID is stale
+                        network_arc.retain(|_, sender| !sender.is_closed());
+                        network_arc.insert(id.clone(), sender);
                         drop(network_arc);
 
                         // Wait for client
-                        let mut client = match cli_rec.recv().await {
+                        // Packets keep coming in the meantime, and a sniffer can't sit on them until it is back
+                        let mut waiting: Vec<Packet> = vec![];
+                        let client = loop {
+                            tokio::select! {
+                                client = cli_rec.recv() => break client,
+                                Some(packet) = receiver.recv() => {
+                                    let mut packet = packet;
+                                    if packet.sniffers.first() == Some(&id) {
+                                        packet.sniffers.remove(0);
+                                        skip_sniffer(&id, vec![packet], "is not connected", &cloned_components, &logger).await;
+                                    } else if packet.data.as_str() != "update"
+                                        || !waiting.iter().any(|p| p.data.as_str() == "update")
+                                    {
+                                        // Everything else waits for us like it would have in the channel
+                                        waiting.push(packet);
+                                    }
+                                }
+                            }
+                        };
+                        let client = match client {
                             Some(client) => client,
                             None => {
-                                logger.error(&format!("Unable to get client from socket. This is an unknown CamelBot error and your component will probably crash/not work. Have a nice day!"));
+                                logger.error("Unable to get client from socket. This is an unknown CamelBot error and your component will probably crash/not work. Have a nice day!");
                                 return;
                             }
                         };
+                        // Put back what we held on to, the session picks it up from the channel
+                        if let Some(component) = cloned_components.lock().await.get(&id) {
+                            for packet in waiting {
+                                // Don't care
+                                let _ = component.sender.send(packet);
+                            }
+                        }
 
                         // Take the halves of the client
-                        let (read, write) = client.split();
+                        let (read, write) = tokio::io::split(client);
 
                         // Run
                         if !Component::run(
                             &mut id,
                             logger.clone(logger.id.clone()),
-                            read,
-                            write,
+                            FramedReader::new(read),
+                            FramedWriter::new(write),
                             cloned_components.clone(),
                             commands.clone(),
                             &mut receiver,
@@ -124,17 +193,16 @@ impl Component {
                             // Remove self from components
                             let mut components = cloned_components.lock().await;
                             components.remove(&id);
+                            // Take our commands with us, the cache keeps them for when we come back
+                            let mut lock = commands.lock().await;
+                            lock.remove(&id);
+                            for left_out in lock.left_out() {
+                                logger.warn(&left_out);
+                            }
                             // Notify other components of the change
                             for (_, v) in components.iter_mut() {
-                                match v.sender.send(Packet {
-                                    source: id.clone(),
-                                    destination: "".to_string(),
-                                    event: "".to_string(),
-                                    data: "update".to_string(),
-                                    sniffers: vec![],
-                                }) {
-                                    _ => {} // Don't care
-                                }
+                                // Don't care
+                                let _ = v.sender.send(Packet::control(&id, "update"));
                             }
                             break;
                         }
@@ -176,12 +244,11 @@ impl Component {
                                 return;
                             }
                         };
-                        let stdout = BufReader::new(stdout);
                         if !Component::run(
                             &mut id,
                             logger.clone(logger.id.clone()),
-                            stdout,
-                            stdin,
+                            FramedReader::new(stdout),
+                            FramedWriter::new(stdin),
                             cloned_components.clone(),
                             commands.clone(),
                             &mut receiver,
@@ -191,18 +258,19 @@ impl Component {
                             // Remove self from components
                             let mut components = cloned_components.lock().await;
                             components.remove(&id);
+                            // Take our commands with us, the cache keeps them for when we come back
+                            let mut lock = commands.lock().await;
+                            lock.remove(&id);
+                            for left_out in lock.left_out() {
+                                logger.warn(&left_out);
+                            }
                             // Notify other components of the change
                             for (_, v) in components.iter_mut() {
-                                match v.sender.send(Packet {
-                                    source: id.clone(),
-                                    destination: "".to_string(),
-                                    event: "".to_string(),
-                                    data: "update".to_string(),
-                                    sniffers: vec![],
-                                }) {
-                                    _ => {} // Don't care
-                                }
+                                // Don't care
+                                let _ = v.sender.send(Packet::control(&id, "update"));
                             }
+                            // Don't care, it might have exited on its own
+                            let _ = cmd.kill().await;
                             break;
                         }
                         match cmd.kill().await {
@@ -235,14 +303,66 @@ impl Component {
         reader: impl ComponentRead,
         writer: impl ComponentWrite,
         components: Arc<Mutex<HashMap<String, Component>>>,
-        commands: Arc<Mutex<Vec<Command>>>,
+        commands: Arc<Mutex<CommandRegistry>>,
         receiver: &mut UnboundedReceiver<Packet>,
     ) -> bool // Should the component be automatically restarted on exit
     {
+        // Packets we are sniffing, waiting for the sniffer to return them before the deadline
+        // The packet is kept with the rest of its chain so it can be passed on if we time out
+        let mut pending: HashMap<u64, (Packet, Instant)> = HashMap::new();
+
+        let restart = Component::session(
+            id,
+            logger.clone(logger.id.clone()),
+            reader,
+            writer,
+            components.clone(),
+            commands,
+            receiver,
+            &mut pending,
+        )
+        .await;
+
+        // However it stopped, it isn't answering anymore
+        set_gucci(&components, id, false).await;
+        // Everyone else has to stop counting on us, sniffers especially
+        for (name, component) in components.lock().await.iter() {
+            if name != id {
+                // Don't care
+                let _ = component.sender.send(Packet::control(id, "update"));
+            }
+        }
+
+        // Whatever we were still sniffing would be lost with us
+        if !pending.is_empty() {
+            let mut packets: Vec<Packet> =
+                pending.into_values().map(|(packet, _)| packet).collect();
+            packets.sort_by_key(|packet| packet.id);
+            skip_sniffer(id, packets, "exited", &components, &logger).await;
+        }
+        restart
+    }
+
+    /// Talks to the component until it exits or is told to stop
+    /// # Arguments
+    /// * `pending` - Where the packets being sniffed are kept, so they can be passed on once it stops
+    /// # Returns
+    /// * Whether the component should continue running
+    #[allow(clippy::too_many_arguments)]
+    async fn session(
+        id: &mut String,
+        logger: ui::Logger,
+        reader: impl ComponentRead,
+        writer: impl ComponentWrite,
+        components: Arc<Mutex<HashMap<String, Component>>>,
+        commands: Arc<Mutex<CommandRegistry>>,
+        receiver: &mut UnboundedReceiver<Packet>,
+        pending: &mut HashMap<u64, (Packet, Instant)>,
+    ) -> bool {
         logger.info(format!("{} has started", id).as_str());
         let mut reader = reader;
         let mut writer = writer;
-        let component_type = components
+        let me = components
             .lock()
             .await
             .get(id)
@@ -250,19 +370,128 @@ impl Component {
                 logger.error("CamelBot has dropped the component thread due to an ID mismatch. Your component will crash and you will be unable to restart it. Have a nice day!");
                 panic!("stupid CamelBot");
             })
-            .component_type;
+            .clone();
+        let component_type = me.component_type;
 
         // We are caching components to avoid bottleknecks and unecessary locks
         let mut component_cache = cache_components(components.clone()).await;
+        let mut subscriptions = SubscriptionIndex::new(&component_cache);
+
+        // Requests we made that are waiting for a response, and requests made to us along with who made them
+        let mut requests: HashMap<u64, PendingRequest> = HashMap::new();
+        // Callers stop waiting at some point, so their requests are forgotten then too
+        let mut callers: HashMap<u64, (String, Instant)> = HashMap::new();
+
+        // Old components send "type_", we only complain about it once
+        let mut warned_legacy = false;
+
+        // Give the component a chance to say hello before anything else
+        // Whatever an old component says first instead is handled once the loop starts
+        let mut first = None;
+        let capabilities = match tokio::time::timeout(HELLO_TIMEOUT, reader.read()).await {
+            Ok(msg) if msg.is_empty() => {
+                // Component has exited
+                return true;
+            }
+            Ok(msg) => match serde_json::from_str::<serde_json::Value>(&msg) {
+                Ok(hello) if hello["type"] == "hello" => {
+                    let hello: HelloPacket = match serde_json::from_value(hello) {
+                        Ok(hello) => hello,
+                        Err(e) => {
+                            logger.error(
+                                format!("{} sent a malformed hello packet: {}", id, e).as_str(),
+                            );
+                            // A bad client shouldn't take a network component away, the next one might get it right
+                            return me.network;
+                        }
+                    };
+                    if let Some(type_) = hello.component_type {
+                        if type_ != component_type {
+                            logger.warn(
+                                format!(
+                                    "{} says it is component type {}, but it is configured as {}",
+                                    id, type_, component_type
+                                )
+                                .as_str(),
+                            );
+                        }
+                    }
+                    match hello.negotiate() {
+                        Ok(capabilities) => {
+                            let to_send = serde_json::to_string(&HelloReplyPacket {
+                                type_: "hello".to_string(),
+                                version: capabilities.version,
+                                capabilities: capabilities.features.clone(),
+                            })
+                            .unwrap();
+                            writer.write(to_send).await;
+                            capabilities
+                        }
+                        Err(error) => {
+                            logger
+                                .error(format!("{} can't connect: {}", id, error.message).as_str());
+                            writer.write(serde_json::to_string(&error).unwrap()).await;
+                            // Restarting a child won't help, but a network component can wait for a client that speaks our version
+                            return me.network;
+                        }
+                    }
+                }
+                _ => {
+                    first = Some(msg);
+                    Capabilities::legacy()
+                }
+            },
+            // Old components might wait for the core to say something first
+            Err(_) => Capabilities::legacy(),
+        };
+        // Only components that know about pings get them, the rest would never answer
+        let heartbeats = capabilities.has("heartbeat");
+        // Interfaces from before diffs only understand the whole list
+        let diffs = capabilities.has("commands_diff");
+        if component_type == 0 && !diffs {
+            // It can't have kept what it was sent before it reconnected, so it gets the whole list again
+            commands.lock().await.forget(id);
+        }
+        if let Some(component) = components.lock().await.get_mut(id) {
+            component.capabilities = capabilities.clone();
+            component.gucci = true;
+        }
+        if component_type == 2 && !capabilities.has("sniffer") {
+            logger.warn(format!("{} is a sniffer but didn't list the sniffer capability in its hello, it won't be sent packets to sniff", id).as_str());
+        }
+        if !capabilities.features.is_empty() {
+            // Everyone else needs to know what we can do before they send us anything
+            for (name, component) in components.lock().await.iter() {
+                if name != id {
+                    // Don't care
+                    let _ = component.sender.send(Packet::control(id, "update"));
+                }
+            }
+        }
+        let period = me.heartbeat_interval.max(Duration::from_millis(1));
+        let mut heartbeat = tokio::time::interval_at(tokio::time::Instant::now() + period, period);
+        heartbeat.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+        // Pings sent since the last pong
+        let mut unanswered: u32 = 0;
 
         loop {
+            let deadline = pending
+                .values()
+                .map(|(_, deadline)| *deadline)
+                .chain(requests.values().map(|request| request.deadline))
+                .chain(callers.values().map(|(_, deadline)| *deadline))
+                .min();
             tokio::select! {
-                msg = reader.read() => {
-                    if msg.len() == 0 {
+                msg = async {
+                    match first.take() {
+                        Some(msg) => msg,
+                        None => reader.read().await,
+                    }
+                } => {
+                    if msg.is_empty() {
                         // Component has exited
                         return true;
                     }
-                    let msg = msg.replace("type_", "type"); // TODO figure out a better way to do this
                     // Attempt to parse msg as JSON
                     let mut msg = match serde_json::from_str::<serde_json::Value>(&msg) {
                         Ok(msg) => msg,
@@ -270,88 +499,46 @@ impl Component {
                             continue;
                         }
                     };
-                    // Add source to msg
-                    match msg.as_object_mut() {
-                        Some(msg) => {
-                            msg.insert("source".to_string(), serde_json::Value::String(id.clone()));
-                        }
-                        None => {
-                            logger.warn("Received a packet that was not in JSON format, could not add source");
-                        }
+                    if legacy_type(&mut msg) && !warned_legacy {
+                        logger.warn(format!("{} is sending \"type_\" instead of \"type\", which will stop working some day", id).as_str());
+                        warned_legacy = true;
                     }
                     let packet_type = match msg["type"].as_str() {
-                        Some(type_) => type_,
+                        Some(type_) => type_.to_string(),
                         _ => {
                             continue;
                         }
                     };
-                    match packet_type {
+                    // Add source to msg
+                    // Sniffers hand back someone else's packet, so its source is left alone
+                    if packet_type != "sniffer" {
+                        match msg.as_object_mut() {
+                            Some(msg) => {
+                                msg.insert("source".to_string(), serde_json::Value::String(id.clone()));
+                            }
+                            None => {
+                                logger.warn("Received a packet that was not in JSON format, could not add source");
+                            }
+                        }
+                    }
+                    match packet_type.as_str() {
                         "event" => {
-                            // Get list of sniffers
-                            let mut sniffers = vec![];
-                            for (v, k) in component_cache.iter_mut() {
-                                if k.component_type == 2 {
-                                    sniffers.push(v.clone());
+                            let event = match msg["event"].as_str() {
+                                Some(event) => event.to_string(),
+                                _ => {
+                                    logger.warn("Received a packet that has no event");
+                                    continue;
                                 }
-                            }
-                            let to_send = crate::packet::Packet {
+                            };
+                            let to_send = Packet {
+                                id: next_id(),
                                 source: id.clone(),
                                 destination: "".to_string(),
-                                event: msg["event"].to_string(),
-                                data: msg.to_string(),
-                                sniffers: sniffers.clone(),
+                                sniffers: sniffer_chain(&component_cache, id, &event, ""),
+                                event,
+                                data: Payload::new(msg.to_string()),
                             };
-
-                            if sniffers.len() > 0 {
-                                // Send packet to the first sniffer
-                                let sniffer = sniffers.remove(0);
-                                match component_cache.get_mut(&sniffer) {
-                                    Some(sniffer) => {
-                                        match sniffer.sender.send(to_send.clone()) {
-                                            Ok(_) => {}
-                                            Err(e) => {
-                                                logger.error(
-                                                    format!(
-                                                        "Failed to send packet to sniffer {}: {}",
-                                                        sniffer.id, e
-                                                    ).as_str(),
-                                                );
-                                            }
-                                        }
-                                    }
-                                    None => {
-                                        logger.error(
-                                            format!(
-                                                "Failed to send packet to sniffer {}: {}",
-                                                sniffer, "sniffer not found"
-                                            ).as_str(),
-                                        );
-                                    }
-
-                                }
-                            } else {
-                                // Broadcast the event to all components that want it
-                                for (_, k) in component_cache.iter_mut() {
-                                    if k.id.as_str() == id {
-                                        continue;
-                                    }
-                                    let event = match msg["event"].as_str() {
-                                        Some(event) => event.to_string(),
-                                        _ => {
-                                            logger.warn("Received a packet that has no event");
-                                            continue;
-                                        }
-                                    };
-                                    if k.intents.contains(&event) {
-                                        match k.sender.send(to_send.clone()) {
-                                            Ok(_) => {},
-                                            Err(e) => {
-                                                logger.error(format!("Failed to send event {} to {}: {}", msg["event"], k.id, e).as_str());
-                                            }
-                                        }
-                                    }
-                                }
-                            }
+                            route_packet(to_send, &component_cache, &subscriptions, &logger);
                         }
                         "send" => {
                             // Get the destination
@@ -362,105 +549,238 @@ impl Component {
                                 }
                             };
 
-                            // Get the sniffers
-                            let mut sniffers = vec![];
-                            for (v, k) in component_cache.iter_mut() {
-                                if k.component_type == 2 {
-                                    sniffers.push(v.clone());
-                                }
-                            }
-                            let to_send = crate::packet::Packet {
+                            let to_send = Packet {
+                                id: next_id(),
                                 source: id.clone(),
                                 destination: destination.to_string(),
                                 event: "".to_string(),
-                                data: msg.to_string(),
-                                sniffers: sniffers.clone(),
+                                data: Payload::new(msg.to_string()),
+                                sniffers: sniffer_chain(&component_cache, id, "", destination),
                             };
-
-                            if sniffers.len() > 0 {
-                                // Send packet to the first sniffer
-                                let sniffer = sniffers.remove(0);
-                                match component_cache.get_mut(&sniffer) {
-                                    Some(sniffer) => {
-                                        match sniffer.sender.send(to_send) {
-                                            _ => {} // Don't care
-                                        }
-                                    }
-                                    None => {
-                                        logger.warn("Sniffer does not contain self, this should not happen");
-                                    }
-                                }
-                            } else {
-                                // Send the packet to the destination
-                                match component_cache.get_mut(destination) {
-                                    Some(destination) => {
-                                        match destination.sender.send(to_send) {
-                                            Ok(_) => {},
-                                            Err(e) => {
-                                                logger.error(format!("Failed to send packet to {}: {}", destination.id, e).as_str());
-                                            }
-                                        }
-                                    }
-                                    None => {
-                                        logger.warn("Packet has bad destination");
-                                    }
-                                }
-                            }
+                            route_packet(to_send, &component_cache, &subscriptions, &logger);
                         }
                         "sniffer" => {
                             // Reconstruct the packet
-                            // Determine if there are any more sniffers to send to
-                            // If there are, send the packet to the next sniffer
-                            // If not, either broadcast the event or send the packet to the destination
-                            todo!();
+                            let returned: SnifferPacket = match serde_json::from_value(msg) {
+                                Ok(returned) => returned,
+                                Err(e) => {
+                                    logger.warn(format!("Received a malformed sniffer packet: {}", e).as_str());
+                                    continue;
+                                }
+                            };
+                            // Make sure we are still waiting on it
+                            // The packet we kept decides where it goes next, the sniffer only gets to change what is in it
+                            let original = match pending.remove(&returned.id) {
+                                Some((original, _)) => original,
+                                None => {
+                                    logger.warn(format!("Sniffer returned packet {} after it timed out or was never sent, ignoring it", returned.id).as_str());
+                                    continue;
+                                }
+                            };
+                            if returned.drop || returned.packet.is_null() {
+                                // The sniffer has eaten the packet
+                                continue;
+                            }
+                            let data = match returned.packet {
+                                serde_json::Value::String(data) => data,
+                                packet => packet.to_string(),
+                            };
+                            // Send it on to the next sniffer, or to wherever it was going if there are none left
+                            route_packet(
+                                Packet {
+                                    data: Payload::new(data),
+                                    ..original
+                                },
+                                &component_cache,
+                                &subscriptions,
+                                &logger,
+                            );
                         }
-                        "intents" => {
-                            // Get the events
-                            let events: Vec<String> = match msg["events"].as_array() {
-                                Some(events) => {
-                                    let mut to_return: Vec<String> = vec![];
-                                    for event in events {
-                                        to_return.push(match event.as_str() {
-                                            Some(event) => event.to_string(),
-                                            _ => {
-                                                logger.error("Received a packet that has an event that is not a string");
-                                                continue;
-                                            }
-                                        });
-                                    }
-                                    to_return
+                        "request" => {
+                            // Get the target
+                            let target = match msg["target"].as_str() {
+                                Some(target) => target.to_string(),
+                                _ => {
+                                    continue;
                                 }
+                            };
+                            let request_id = next_id();
+                            let nonce = msg["nonce"].clone();
+                            let timeout = match msg["timeout"].as_u64() {
+                                Some(timeout) => Duration::from_millis(timeout),
+                                None => REQUEST_TIMEOUT,
+                            };
+                            let to_send = match serde_json::to_string(&RequestPacket {
+                                type_: "request".to_string(),
+                                id: request_id,
+                                source: id.clone(),
+                                timeout: timeout.as_millis() as u64,
+                                data: msg["data"].clone(),
+                            }) {
+                                Ok(value) => value,
                                 _ => {
+                                    logger.warn("Failed to convert to JSON");
                                     continue;
                                 }
                             };
 
-                            let mut found_commands: Vec<Command> = match msg["commands"].as_array() {
-                                Some(cmds) => {
-                                    let mut to_return: Vec<Command> = vec![];
-                                    for i in cmds {
-                                        to_return.push(match serde_json::from_value(i.clone()) {
-                                            Ok(value) => Command {
-                                                source: id.clone(),
-                                                structure: value,
-                                            },
-                                            _ => {
-                                                continue;
-                                            }
-                                        })
+                            // Requests skip the sniffers, they are between the two components
+                            let error = match component_cache.get(&target) {
+                                Some(destination) if !destination.capabilities.has("rpc") => "target does not support requests",
+                                Some(destination) => match destination.sender.send(Packet {
+                                    id: request_id,
+                                    source: id.clone(),
+                                    destination: target.clone(),
+                                    event: "request".to_string(),
+                                    data: Payload::new(to_send),
+                                    sniffers: vec![],
+                                }) {
+                                    Ok(_) => {
+                                        requests.insert(request_id, PendingRequest {
+                                            target,
+                                            nonce,
+                                            deadline: Instant::now() + timeout,
+                                        });
+                                        continue;
                                     }
-                                    to_return
+                                    Err(_) => "target has exited",
                                 },
-                                None => continue,
+                                None => "target not found",
+                            };
+                            let to_send = ResponsePacket::error(request_id, &target, nonce, error);
+                            if let Ok(to_send) = serde_json::to_string(&to_send) {
+                                writer.write(to_send).await;
+                            }
+                        }
+                        "response" => {
+                            // Find out who asked
+                            let request_id = match msg["id"].as_u64() {
+                                Some(request_id) => request_id,
+                                _ => {
+                                    continue;
+                                }
+                            };
+                            let caller = match callers.remove(&request_id) {
+                                Some((caller, _)) => caller,
+                                None => {
+                                    logger.warn(format!("Received a response to request {}, which was never made, was already answered or timed out", request_id).as_str());
+                                    continue;
+                                }
+                            };
+                            let to_send = ResponsePacket {
+                                type_: "response".to_string(),
+                                id: request_id,
+                                source: id.clone(),
+                                nonce: serde_json::Value::Null, // The caller fills this in
+                                data: msg["data"].clone(),
+                                error: msg["error"].as_str().map(|e| e.to_string()),
+                            };
+                            let to_send = match serde_json::to_string(&to_send) {
+                                Ok(value) => value,
+                                _ => {
+                                    logger.warn("Failed to convert to JSON");
+                                    continue;
+                                }
+                            };
+                            match component_cache.get(&caller) {
+                                Some(caller) => {
+                                    // Don't care, the caller will time out if it is gone
+                                    let _ = caller.sender.send(Packet {
+                                        id: request_id,
+                                        source: id.clone(),
+                                        destination: caller.id.clone(),
+                                        event: "response".to_string(),
+                                        data: Payload::new(to_send),
+                                        sniffers: vec![],
+                                    });
+                                }
+                                None => {
+                                    logger.warn(format!("{} is no longer around to receive its response", caller).as_str());
+                                }
+                            }
+                        }
+                        "intents" => {
+                            let intent = match Intent::new(msg) {
+                                Ok(intent) => intent,
+                                Err(e) => {
+                                    logger.error(format!("Rejected intents, {} is invalid: {}", e.field, e.message).as_str());
+                                    // Let the component know what it did wrong
+                                    let to_send = IntentErrorPacket {
+                                        type_: "intents_error".to_string(),
+                                        field: e.field,
+                                        message: e.message,
+                                    };
+                                    if let Ok(to_send) = serde_json::to_string(&to_send) {
+                                        writer.write(to_send).await;
+                                    }
+                                    continue;
+                                }
                             };
-                            // Put the events in the arc
+                            let mut found_commands = vec![];
+                            for mut structure in intent.commands {
+                                if structure.plugin.is_empty() {
+                                    structure.plugin = id.clone();
+                                }
+                                found_commands.push(structure);
+                            }
+                            // Swap out whatever commands we had before
                             let mut lock = commands.lock().await;
-                            lock.append(&mut found_commands);
+                            let conflicts = lock.replace(id, found_commands);
+                            for left_out in lock.left_out() {
+                                logger.warn(&left_out);
+                            }
+                            for conflict in conflicts {
+                                let mut owners = conflict.losers.clone();
+                                if let Some(winner) = &conflict.winner {
+                                    owners.insert(0, winner.clone());
+                                }
+                                logger.warn(
+                                    format!(
+                                        "Command {} was registered by {}, {}",
+                                        conflict.name,
+                                        owners.join(", "),
+                                        match &conflict.winner {
+                                            Some(winner) => format!("{} keeps it", winner),
+                                            None => "they have been namespaced".to_string(),
+                                        }
+                                    )
+                                    .as_str(),
+                                );
+                                // Let the losers know what happened to their command
+                                for loser in conflict.losers.iter() {
+                                    let to_send = ConflictPacket {
+                                        type_: "command_conflict".to_string(),
+                                        name: conflict.name.clone(),
+                                        winner: conflict.winner.clone(),
+                                        renamed: lock.resolved_name(loser, &conflict.name),
+                                    };
+                                    let to_send = match serde_json::to_string(&to_send) {
+                                        Ok(value) => value,
+                                        _ => {
+                                            continue;
+                                        }
+                                    };
+                                    if let Some(loser) = component_cache.get(loser) {
+                                        // Don't care
+                                        let _ = loser.sender.send(Packet {
+                                            id: next_id(),
+                                            source: "core".to_string(),
+                                            destination: loser.id.clone(),
+                                            event: "".to_string(),
+                                            data: Payload::new(to_send),
+                                            sniffers: vec![],
+                                        });
+                                    }
+                                }
+                            }
                             drop(lock);
                             let mut lock = components.lock().await;
                             match lock.get_mut(id) {
                                 Some(component) => {
-                                    component.intents = events;
+                                    component.intents = intent.events;
+                                    component.sources = intent.components;
+                                    component.messages = intent.messages;
+                                    component.sniffer = intent.sniffer;
                                 }
                                 None => {
                                     logger.error("Received intents for a component that does not exist");
@@ -469,18 +789,121 @@ impl Component {
                             drop(lock);
                             // Send an update packet to each component
                             for (_, v) in component_cache.iter_mut() {
-                                match v.sender.send(Packet {
-                                    source: id.clone(),
-                                    destination: "".to_string(),
-                                    event: "".to_string(),
-                                    data: "update".to_string(),
-                                    sniffers: vec![],
-                                }) {
-                                    _ => {} // Don't care
-                                }
+                                // Don't care
+                                let _ = v.sender.send(Packet::control(id, "update"));
                             }
                             // Save the command cache
-                            commands::save_cache(commands.lock().await.to_vec()).await;
+                            if let Err(e) = commands::save_cache(&*commands.lock().await).await {
+                                logger.error(format!("Failed to save the command cache: {}", e).as_str());
+                            }
+                        }
+                        "pong" => {
+                            // Still alive
+                            if unanswered > 1 {
+                                logger.info(format!("{} is answering heartbeats again", id).as_str());
+                                set_gucci(&components, id, true).await;
+                            }
+                            unanswered = 0;
+                        }
+                        "ping" => {
+                            // Components can check on the core too
+                            let to_send = serde_json::to_string(&PingPacket {
+                                type_: "pong".to_string(),
+                                nonce: msg["nonce"].clone(),
+                            })
+                            .unwrap();
+                            writer.write(to_send).await;
+                        }
+                        "framing" => {
+                            // The component wants to switch how packets are split up or encoded
+                            let mut framing: FramingPacket = match serde_json::from_value(msg) {
+                                Ok(framing) => framing,
+                                Err(e) => {
+                                    logger.warn(format!("Received a malformed framing packet: {}", e).as_str());
+                                    continue;
+                                }
+                            };
+                            let needed = framing.encoding.capability().unwrap_or("framing");
+                            if !capabilities.has("framing") || !capabilities.has(needed) {
+                                logger.warn(format!("{} asked for framing it didn't list in its hello, ignoring the framing packet", id).as_str());
+                                continue;
+                            }
+                            if framing.mode == Framing::Lines && framing.encoding != Encoding::Json {
+                                logger.warn("Binary encodings need length framing, ignoring the framing packet");
+                                continue;
+                            }
+                            reader.set_framing(framing.mode, framing.encoding);
+                            // The answer still goes out the old way
+                            framing.type_ = "framing".to_string();
+                            let to_send = serde_json::to_string(&framing).unwrap();
+                            writer.write(to_send).await;
+                            writer.set_framing(framing.mode, framing.encoding);
+                        }
+                        "invoke" => {
+                            let invocation: Invocation = match serde_json::from_value(msg) {
+                                Ok(invocation) => invocation,
+                                Err(e) => {
+                                    logger.warn(format!("Received a malformed invoke packet: {}", e).as_str());
+                                    continue;
+                                }
+                            };
+                            let name = invocation.name.clone();
+                            let nonce = invocation.nonce.clone();
+                            if let Err(e) = route_invocation(invocation, id, &commands, &component_cache).await {
+                                writer.write(e.packet(name, nonce)).await;
+                            }
+                        }
+                        "text" => {
+                            // A plain chat message that might be a command
+                            let message = match msg["message"].as_str() {
+                                Some(message) => message,
+                                _ => {
+                                    continue;
+                                }
+                            };
+                            let parsed = parser::parse(message, &*commands.lock().await);
+                            // Errors name the command like the invoke packet does
+                            let (name, result) = match parsed {
+                                Some(Ok((command, options))) => {
+                                    let invocation = Invocation {
+                                        name: command.clone(),
+                                        options,
+                                        nonce: msg["nonce"].clone(),
+                                        context: msg["context"].clone(),
+                                    };
+                                    (command, route_invocation(invocation, id, &commands, &component_cache).await)
+                                }
+                                Some(Err(e)) => (e.name, Err(InvokeError::Invalid(e.message))),
+                                None => {
+                                    // Just chatter
+                                    continue;
+                                }
+                            };
+                            if let Err(e) = result {
+                                writer.write(e.packet(name, msg["nonce"].clone())).await;
+                            }
+                        }
+                        "commands" => {
+                            // The interface wants the whole list again
+                            let mut lock = commands.lock().await;
+                            lock.forget(id);
+                            let to_send = lock.update_for(id, diffs);
+                            drop(lock);
+                            if let Some(to_send) = to_send {
+                                writer.write(to_send).await;
+                            }
+                        }
+                        "commands_ack" => {
+                            // The interface registered what it was last sent
+                            if !capabilities.has("commands_ack") {
+                                logger.warn(format!("{} sent a commands ack without listing commands_ack in its hello, ignoring it", id).as_str());
+                                continue;
+                            }
+                            let mut lock = commands.lock().await;
+                            lock.acknowledge(id);
+                            if let Err(e) = commands::save_cache(&lock).await {
+                                logger.error(format!("Failed to save the command cache: {}", e).as_str());
+                            }
                         }
                         "id" => {
                             // Get the id
@@ -492,7 +915,7 @@ impl Component {
                             };
 
                             // Get self from the cache
-                            let component = match component_cache.get_mut(id) {
+                            let mut component = match component_cache.get_mut(id) {
                                 Some(component) => component.clone(),
                                 None => {
                                     logger.error("Received an id for a component that does not exist");
@@ -503,20 +926,17 @@ impl Component {
                             let mut lock = components.lock().await;
                             lock.remove(id);
                             // Add self to the cache
+                            component.id = changed_id.to_string();
                             lock.insert(changed_id.to_string(), component);
+                            drop(lock);
+                            // Our commands move with us
+                            commands.lock().await.rename(id, changed_id);
                             *id = changed_id.to_string();
 
                             // Notify all components of the change
                             for (_, v) in component_cache.iter_mut() {
-                                match v.sender.send(Packet {
-                                    source: id.clone(),
-                                    destination: "".to_string(),
-                                    event: "".to_string(),
-                                    data: "update".to_string(),
-                                    sniffers: vec![],
-                                }) {
-                                    _ => {} // Don't care
-                                }
+                                // Don't care
+                                let _ = v.sender.send(Packet::control(id, "update"));
                             }
 
                         }
@@ -566,6 +986,62 @@ impl Component {
                     }
 
                 }
+                _ = heartbeat.tick(), if heartbeats => {
+                    if unanswered >= me.missed_heartbeats {
+                        logger.error(format!("{} has missed {} heartbeats and is considered dead", id, unanswered).as_str());
+                        return me.on_dead == DeadPolicy::Reconnect;
+                    }
+                    if unanswered == 1 {
+                        logger.warn(format!("{} did not answer a heartbeat", id).as_str());
+                        set_gucci(&components, id, false).await;
+                    }
+                    unanswered += 1;
+                    let to_send = serde_json::to_string(&PingPacket {
+                        type_: "ping".to_string(),
+                        nonce: serde_json::Value::from(next_id()),
+                    })
+                    .unwrap();
+                    writer.write(to_send).await;
+                }
+                _ = sleep_until_next(deadline), if deadline.is_some() => {
+                    let now = Instant::now();
+                    // Deal with the packets that we sat on for too long
+                    let expired: Vec<u64> = pending
+                        .iter()
+                        .filter(|(_, (_, deadline))| *deadline <= now)
+                        .map(|(packet_id, _)| *packet_id)
+                        .collect();
+                    for packet_id in expired {
+                        let (packet, _) = match pending.remove(&packet_id) {
+                            Some(packet) => packet,
+                            None => continue,
+                        };
+                        if me.fail_open {
+                            logger.warn(format!("Sniffer {} timed out on packet {}, skipping it", id, packet_id).as_str());
+                            route_packet(packet, &component_cache, &subscriptions, &logger);
+                        } else {
+                            logger.warn(format!("Sniffer {} timed out on packet {}, dropping it", id, packet_id).as_str());
+                        }
+                    }
+                    // Give up on requests that never got an answer
+                    let expired: Vec<u64> = requests
+                        .iter()
+                        .filter(|(_, request)| request.deadline <= now)
+                        .map(|(request_id, _)| *request_id)
+                        .collect();
+                    for request_id in expired {
+                        let request = match requests.remove(&request_id) {
+                            Some(request) => request,
+                            None => continue,
+                        };
+                        let to_send = ResponsePacket::error(request_id, &request.target, request.nonce, "timed out");
+                        if let Ok(to_send) = serde_json::to_string(&to_send) {
+                            writer.write(to_send).await;
+                        }
+                    }
+                    // The callers have given up on these by now
+                    callers.retain(|_, (_, deadline)| *deadline > now);
+                }
                 packet = receiver.recv() => {
                     let packet = match packet {
                         Some(packet) => packet,
@@ -582,31 +1058,73 @@ impl Component {
                         }
                         "update" => {
                             component_cache = cache_components(components.clone()).await;
-                            let lock = commands.lock().await;
-                            let command_clone = lock.clone();
-                            drop(lock);
-                            writer.write(commands::create_packet(command_clone)).await;
+                            subscriptions = SubscriptionIndex::new(&component_cache);
+                            // Only interfaces need to know about commands, and only when they change
+                            if component_type == 0 {
+                                let mut lock = commands.lock().await;
+                                let to_send = lock.update_for(id, diffs);
+                                drop(lock);
+                                if let Some(to_send) = to_send {
+                                    writer.write(to_send).await;
+                                }
+                            }
                         }
                         _ => {
-                            if component_type == 2 {
-                                // We do be a sniffer
-                                let mut sniffers = packet.sniffers.clone();
-                                // Remove self from sniffers
-                                let index = match sniffers.iter().position(|x| x == id) {
-                                    Some(index) => index,
+                            if packet.event == "request" && packet.destination == *id {
+                                // Remember who to send the response to, for as long as they wait for it
+                                let timeout = serde_json::from_str::<serde_json::Value>(packet.data.as_str())
+                                    .ok()
+                                    .and_then(|request| request["timeout"].as_u64())
+                                    .map(Duration::from_millis)
+                                    .unwrap_or(REQUEST_TIMEOUT);
+                                callers.insert(packet.id, (packet.source.clone(), Instant::now() + timeout));
+                                writer.write_payload(&packet.data).await;
+                            } else if packet.event == "response" && packet.destination == *id {
+                                let request = match requests.remove(&packet.id) {
+                                    Some(request) => request,
                                     None => {
+                                        // We already gave up on it
+                                        continue;
+                                    }
+                                };
+                                let mut response: ResponsePacket = match serde_json::from_str(packet.data.as_str()) {
+                                    Ok(response) => response,
+                                    _ => {
                                         continue;
                                     }
                                 };
-                                sniffers.remove(index);
+                                response.nonce = request.nonce;
+                                if let Ok(to_send) = serde_json::to_string(&response) {
+                                    writer.write(to_send).await;
+                                }
+                            } else if component_type == 2 && packet.sniffers.first() == Some(id) {
+                                // We do be a sniffer, and this packet is ours to sniff
+                                let mut sniffers = packet.sniffers.clone();
+                                // Remove self from sniffers
+                                sniffers.remove(0);
+
+                                // Keep the packet around in case we never return it
+                                pending.insert(
+                                    packet.id,
+                                    (
+                                        Packet {
+                                            sniffers: sniffers.clone(),
+                                            ..packet.clone()
+                                        },
+                                            Instant::now() + me.sniffer_timeout,
+                                    ),
+                                );
 
-                                let to_send = crate::packet::SnifferPacket {
+                                let to_send = SnifferPacket {
                                     type_: "sniffer".to_string(),
+                                    id: packet.id,
                                     source: packet.source,
                                     destination: packet.destination,
                                     event: packet.event,
                                     sniffers,
-                                    packet: packet.data,
+                                    packet: serde_json::from_str(packet.data.as_str())
+                                        .unwrap_or(serde_json::Value::String(packet.data.as_str().to_string())),
+                                    drop: false,
                                 };
                                 // Convert to JSON
                                 let to_send = match serde_json::to_string(&to_send) {
@@ -619,8 +1137,8 @@ impl Component {
 
                                 writer.write(to_send).await;
 
-                            } else{
-                                writer.write(packet.data).await;
+                            } else {
+                                writer.write_payload(&packet.data).await;
                             }
                         }
                     }
@@ -630,15 +1148,8 @@ impl Component {
         }
     }
     pub async fn kill(&self) {
-        match self.sender.send(Packet {
-            source: "".to_string(),
-            destination: "".to_string(),
-            event: "".to_string(),
-            data: "kill".to_string(),
-            sniffers: vec![],
-        }) {
-            _ => {} // Don't care
-        }
+        // Don't care
+        let _ = self.sender.send(Packet::control("", "kill"));
     }
 }
 
@@ -651,11 +1162,27 @@ impl Clone for Component {
             key: self.key.clone(),
             sender: self.sender.clone(),
             intents: self.intents.clone(),
+            sources: self.sources.clone(),
+            messages: self.messages,
+            sniffer: self.sniffer.clone(),
+            sniffer_timeout: self.sniffer_timeout,
+            fail_open: self.fail_open,
+            capabilities: self.capabilities.clone(),
+            heartbeat_interval: self.heartbeat_interval,
+            missed_heartbeats: self.missed_heartbeats,
+            on_dead: self.on_dead,
             gucci: self.gucci,
         }
     }
 }
 
+/// Marks whether a component is answering its pings, for the TUI
+async fn set_gucci(components: &Arc<Mutex<HashMap<String, Component>>>, id: &str, gucci: bool) {
+    if let Some(component) = components.lock().await.get_mut(id) {
+        component.gucci = gucci;
+    }
+}
+
 pub async fn cache_components(
     components: Arc<Mutex<HashMap<String, Component>>>,
 ) -> HashMap<String, Component> {
@@ -668,89 +1195,393 @@ pub async fn cache_components(
     component_cache
 }
 
-#[async_trait]
-pub trait ComponentRead {
-    async fn read(&mut self) -> String;
+/// Passes on packets a sniffer won't be returning, or drops them if the sniffer fails closed
+/// # Arguments
+/// * `id` - The ID of the sniffer
+/// * `packets` - The packets, with the sniffer already taken out of their chains
+/// * `why` - Why the sniffer won't return them, for the log
+/// * `components` - The components to route the packets to
+/// * `logger` - The logger of the sniffer
+async fn skip_sniffer(
+    id: &str,
+    packets: Vec<Packet>,
+    why: &str,
+    components: &Arc<Mutex<HashMap<String, Component>>>,
+    logger: &ui::Logger,
+) {
+    let fail_open = components
+        .lock()
+        .await
+        .get(id)
+        .map(|component| component.fail_open)
+        .unwrap_or(true);
+    if !fail_open {
+        logger.warn(
+            format!(
+                "Sniffer {} {} with {} packets, dropping them",
+                id,
+                why,
+                packets.len()
+            )
+            .as_str(),
+        );
+        return;
+    }
+    logger.warn(
+        format!(
+            "Sniffer {} {} with {} packets, skipping it for them",
+            id,
+            why,
+            packets.len()
+        )
+        .as_str(),
+    );
+    let component_cache = cache_components(components.clone()).await;
+    let subscriptions = SubscriptionIndex::new(&component_cache);
+    for packet in packets {
+        route_packet(packet, &component_cache, &subscriptions, logger);
+    }
 }
-#[async_trait]
-impl ComponentRead for ReadHalf<'_> {
-    async fn read(&mut self) -> String {
-        let mut to_return = "".to_string();
-        loop {
-            let mut buffer = [1];
-            let _ = match AsyncReadExt::read(&mut self, &mut buffer).await {
-                Ok(n) => n,
-                Err(_) => {
-                    return "".to_string();
+
+/// Sleeps until the next sniffed packet or request times out
+async fn sleep_until_next(deadline: Option<Instant>) {
+    match deadline {
+        Some(deadline) => tokio::time::sleep_until(deadline.into()).await,
+        None => std::future::pending().await,
+    }
+}
+
+/// Lists the sniffers a packet has to pass through before it is delivered, in the order they sniff it
+/// # Arguments
+/// * `component_cache` - The cached components to pick sniffers from
+/// * `source` - The component the packet came from, which never sniffs its own packets
+/// * `event` - The event of the packet, empty if it has a destination
+/// * `destination` - The destination of the packet, empty if it is broadcast
+pub fn sniffer_chain(
+    component_cache: &HashMap<String, Component>,
+    source: &str,
+    event: &str,
+    destination: &str,
+) -> Vec<String> {
+    let mut sniffers = vec![];
+    for (v, k) in component_cache.iter() {
+        // Sniffers that never said they can hand packets back would sit on every one of them
+        // and so would sniffers that aren't connected or answering
+        if k.component_type == 2
+            && v != source
+            && k.gucci
+            && k.capabilities.has("sniffer")
+            && k.sniffer.wants(event, destination)
+        {
+            sniffers.push(k);
+        }
+    }
+    // HashMap order is random, so sort to keep the chain the same from packet to packet
+    sniffers.sort_by(|a, b| {
+        b.sniffer
+            .priority
+            .cmp(&a.sniffer.priority)
+            .then_with(|| a.id.cmp(&b.id))
+    });
+    sniffers.iter().map(|k| k.id.clone()).collect()
+}
+
+/// Sends a packet to the next sniffer in its chain.
+/// Once no sniffers are left, the packet is sent to its destination, or broadcast by its event if it has none.
+/// # Arguments
+/// * `packet` - The packet to route
+/// * `component_cache` - The cached components to route to
+/// * `subscriptions` - Who wants which events, built from the cached components
+/// * `logger` - The logger of the component doing the routing
+pub fn route_packet(
+    packet: Packet,
+    component_cache: &HashMap<String, Component>,
+    subscriptions: &SubscriptionIndex,
+    logger: &ui::Logger,
+) {
+    let mut packet = packet;
+    if let Some(sniffer) = packet.sniffers.first() {
+        let returned = match component_cache.get(sniffer) {
+            Some(sniffer) => match sniffer.sender.send(packet) {
+                Ok(_) => return,
+                Err(e) => {
+                    logger.error(
+                        format!("Failed to send packet to sniffer {}: {}", sniffer.id, e).as_str(),
+                    );
+                    e.0
                 }
-            };
-            let char = buffer[0] as char;
-            if char == '\n' {
-                break;
-            } else {
-                to_return.push(char);
-                if to_return.len() > 10000 {
-                    // We dead bro
-                    return "".to_string();
+            },
+            None => {
+                logger.error(
+                    format!(
+                        "Failed to send packet to sniffer {}: {}",
+                        sniffer, "sniffer not found"
+                    )
+                    .as_str(),
+                );
+                packet
+            }
+        };
+        // The sniffer is gone, skip it rather than lose the packet
+        packet = returned;
+        packet.sniffers.remove(0);
+        route_packet(packet, component_cache, subscriptions, logger);
+        return;
+    }
+
+    if !packet.destination.is_empty() {
+        // Send the packet to the destination
+        match component_cache.get(&packet.destination) {
+            Some(destination) if !destination.messages => {
+                delivery_failed(
+                    &packet,
+                    "target does not accept messages",
+                    component_cache,
+                    logger,
+                );
+            }
+            Some(destination) => {
+                if let Err(e) = destination.sender.send(packet) {
+                    logger.error(
+                        format!("Failed to send packet to {}: {}", destination.id, e).as_str(),
+                    );
+                    delivery_failed(&e.0, "target has exited", component_cache, logger);
                 }
             }
+            None => {
+                logger.warn(format!("Packet has bad destination {}", packet.destination).as_str());
+                delivery_failed(&packet, "target not found", component_cache, logger);
+            }
         }
-        to_return
+        return;
     }
-}
-#[async_trait]
-impl ComponentRead for BufReader<ChildStdout> {
-    async fn read(&mut self) -> String {
-        let mut buf = String::new();
-        match self.read_line(&mut buf).await {
-            Ok(_) => {}
-            Err(_) => {
-                return "".to_string();
-            }
+
+    // Broadcast the event to all components that want it
+    for name in subscriptions.subscribers(&packet.event) {
+        if name == packet.source {
+            continue;
+        }
+        let k = match component_cache.get(&name) {
+            Some(k) => k,
+            None => continue,
+        };
+        if !k.sources.is_empty() && !k.sources.contains(&packet.source) {
+            // Not from a component it listens to
+            continue;
+        }
+        if let Err(e) = k.sender.send(packet.clone()) {
+            logger.error(
+                format!("Failed to send event {} to {}: {}", packet.event, k.id, e).as_str(),
+            );
         }
-        buf
     }
 }
 
-#[async_trait]
-pub trait ComponentWrite {
-    async fn write(&mut self, msg: String);
-}
-#[async_trait]
-impl ComponentWrite for WriteHalf<'_> {
-    async fn write(&mut self, msg: String) {
-        let msg = msg.replace("type_", "type");
-        let msg = format!("{}\n", msg);
-        match AsyncWriteExt::write(&mut self, msg.as_bytes()).await {
-            Ok(_) => {}
-            Err(_) => {
-                return;
-            }
+/// Sends an invocation to the component that registered the command
+/// # Arguments
+/// * `invocation` - The invocation from the interface
+/// * `interface` - The ID of the interface it came from
+/// * `commands` - The registered commands
+/// * `component_cache` - The cached components to find the owner in
+/// # Returns
+/// * Why the invocation couldn't be routed, was denied or was rate limited, if it was
+async fn route_invocation(
+    mut invocation: Invocation,
+    interface: &str,
+    commands: &Arc<Mutex<CommandRegistry>>,
+    component_cache: &HashMap<String, Component>,
+) -> Result<(), InvokeError> {
+    let command = match commands.lock().await.find(&invocation.name) {
+        Some(command) => command,
+        None => return Err(InvokeError::Invalid("unknown command".to_string())),
+    };
+    commands::validate(&command.structure, &invocation.options)?;
+    // Macros fill in the rest
+    invocation.options.extend(command.preset.clone());
+    let mut lock = commands.lock().await;
+    lock.permitted(&command, interface, &invocation.context)
+        .map_err(InvokeError::Denied)?;
+    lock.rate_limit(&command, interface, &invocation.context)
+        .map_err(InvokeError::Cooldown)?;
+    drop(lock);
+    // Only invocations that make it somewhere count against the rate limit
+    let context = invocation.context.clone();
+
+    // The core answers its own commands
+    if command.source == "core" {
+        let name = invocation.options.get("command").and_then(|c| c.as_str());
+        let message = commands::help_text(&*commands.lock().await, name)?;
+        let to_send = serde_json::to_string(&ReplyPacket {
+            type_: "event".to_string(),
+            event: "reply".to_string(),
+            data: ReplyData {
+                message,
+                nonce: invocation.nonce,
+                context: invocation.context,
+            },
+        })
+        .map_err(|e| InvokeError::Invalid(e.to_string()))?;
+        if let Some(interface) = component_cache.get(interface) {
+            // Don't care, the interface is gone if this fails
+            let _ = interface.sender.send(Packet {
+                id: next_id(),
+                source: "core".to_string(),
+                destination: interface.id.clone(),
+                event: "".to_string(),
+                data: Payload::new(to_send),
+                sniffers: vec![],
+            });
         }
-        match self.flush().await {
-            Ok(_) => {}
-            Err(_) => {
-                return;
-            }
+        commands.lock().await.record(&command, interface, &context);
+        return Ok(());
+    }
+
+    let owner = match component_cache.get(&command.source) {
+        Some(owner) => owner,
+        None => {
+            return Err(InvokeError::Invalid(format!(
+                "{} is not running",
+                command.source
+            )))
         }
+    };
+    let to_send = serde_json::to_string(&InvokePacket {
+        type_: "invoke".to_string(),
+        name: command.name.clone(),
+        options: invocation.options,
+        interface: interface.to_string(),
+        nonce: invocation.nonce,
+        context: invocation.context,
+    })
+    .map_err(|e| InvokeError::Invalid(e.to_string()))?;
+    match owner.sender.send(Packet {
+        id: next_id(),
+        source: interface.to_string(),
+        destination: owner.id.clone(),
+        event: "".to_string(),
+        data: Payload::new(to_send),
+        sniffers: vec![],
+    }) {
+        Ok(_) => {
+            commands.lock().await.record(&command, interface, &context);
+            Ok(())
+        }
+        Err(_) => Err(InvokeError::Invalid(format!(
+            "{} has exited",
+            command.source
+        ))),
     }
 }
-#[async_trait]
-impl ComponentWrite for ChildStdin {
-    async fn write(&mut self, msg: String) {
-        let msg = msg.replace("type_", "type");
-        let msg = format!("{}\n", msg);
-        match self.write_all(msg.as_bytes()).await {
-            Ok(_) => {}
-            Err(_) => {
-                return;
-            }
+
+/// Lets the source of a packet know that it couldn't be delivered
+/// # Arguments
+/// * `packet` - The packet that couldn't be delivered
+/// * `reason` - Why it couldn't be delivered
+/// * `component_cache` - The cached components to find the source in
+/// * `logger` - The logger of the component doing the routing
+fn delivery_failed(
+    packet: &Packet,
+    reason: &str,
+    component_cache: &HashMap<String, Component>,
+    logger: &ui::Logger,
+) {
+    let source = match component_cache.get(&packet.source) {
+        Some(source) => source,
+        None => {
+            // Nobody left to tell
+            return;
         }
-        match self.flush().await {
-            Ok(_) => {}
-            Err(_) => {
-                return;
-            }
+    };
+    // Hand back the nonce the source put on the packet, if it put one there
+    let nonce = match serde_json::from_str::<serde_json::Value>(packet.data.as_str()) {
+        Ok(data) => data["nonce"].clone(),
+        Err(_) => serde_json::Value::Null,
+    };
+    let to_send = match serde_json::to_string(&DeliveryFailedPacket {
+        type_: "delivery_failed".to_string(),
+        id: packet.id,
+        nonce,
+        target: packet.destination.clone(),
+        reason: reason.to_string(),
+    }) {
+        Ok(value) => value,
+        _ => {
+            logger.warn("Failed to convert to JSON");
+            return;
         }
+    };
+    // Don't care, the source is gone if this fails
+    let _ = source.sender.send(Packet {
+        id: packet.id,
+        source: "core".to_string(),
+        destination: source.id.clone(),
+        event: "".to_string(),
+        data: Payload::new(to_send),
+        sniffers: vec![],
+    });
+}
+
+#[async_trait]
+pub trait ComponentRead {
+    async fn read(&mut self) -> String;
+    fn set_framing(&mut self, framing: Framing, encoding: Encoding);
+}
+
+#[async_trait]
+pub trait ComponentWrite {
+    async fn write(&mut self, msg: String);
+    async fn write_payload(&mut self, payload: &Payload);
+    fn set_framing(&mut self, framing: Framing, encoding: Encoding);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sniffer(name: &str, priority: i32, gucci: bool) -> Component {
+        let (sender, _) = unbounded_channel();
+        let mut component = Component::new(name.to_string(), 2, "".to_string(), sender);
+        component.capabilities.features = vec!["sniffer".to_string()];
+        component.sniffer.priority = priority;
+        component.gucci = gucci;
+        component
+    }
+
+    fn cache(components: Vec<Component>) -> HashMap<String, Component> {
+        components
+            .into_iter()
+            .map(|component| (component.id.clone(), component))
+            .collect()
+    }
+
+    #[test]
+    fn chain_is_ordered_by_priority() {
+        let cache = cache(vec![
+            sniffer("b", 0, true),
+            sniffer("a", 0, true),
+            sniffer("c", 5, true),
+        ]);
+        assert_eq!(sniffer_chain(&cache, "x", "message", ""), ["c", "a", "b"]);
+    }
+
+    #[test]
+    fn chain_skips_the_source() {
+        let cache = cache(vec![sniffer("a", 0, true), sniffer("b", 0, true)]);
+        assert_eq!(sniffer_chain(&cache, "a", "message", ""), ["b"]);
+    }
+
+    #[test]
+    fn chain_skips_dead_sniffers() {
+        let cache = cache(vec![sniffer("alive", 0, true), sniffer("dead", 10, false)]);
+        assert_eq!(sniffer_chain(&cache, "x", "message", ""), ["alive"]);
+        assert_eq!(sniffer_chain(&cache, "x", "", "y"), ["alive"]);
+    }
+
+    #[test]
+    fn chain_skips_sniffers_without_the_capability() {
+        let mut legacy = sniffer("legacy", 0, true);
+        legacy.capabilities = Capabilities::legacy();
+        let cache = cache(vec![legacy, sniffer("new", 0, true)]);
+        assert_eq!(sniffer_chain(&cache, "x", "message", ""), ["new"]);
     }
 }
@@ -1,17 +1,72 @@
 // jkcoxson
 
 use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
 use tokio::{
     fs::File,
     io::{AsyncReadExt, AsyncWriteExt},
 };
 
+use crate::{
+    commands::{Macro, RateLimit},
+    permissions::Permissions,
+    tls::TlsConfig,
+};
+
+#[cfg(unix)]
+use crate::unix::SocketConfig;
+
 #[derive(Serialize, Deserialize)]
 pub struct Config {
     pub tcp: bool,
     pub port: u16,
     pub host: String,
     pub components: Vec<ComponentConstructor>,
+    // Whether TCP components can send their key in plain text instead of answering a challenge
+    #[serde(default)]
+    pub allow_plaintext_keys: bool,
+    // Certificates for encrypting TCP connections, plain TCP if left out
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tls: Option<TlsConfig>,
+    // A Unix socket for components on this machine, not created if left out
+    #[cfg(unix)]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub socket: Option<SocketConfig>,
+    // What to do when two components register a command with the same name
+    #[serde(default)]
+    pub command_conflicts: ConflictPolicy,
+    // Command name -> the component that gets to keep it when there is a conflict
+    #[serde(default)]
+    pub command_overrides: HashMap<String, String>,
+    // What plain text messages start with to be parsed as commands, for interfaces without their own commands
+    #[serde(default = "default_command_prefix")]
+    pub command_prefix: String,
+    // Command name -> how often it can be invoked, replacing whatever the plugin asked for
+    // This is the name the plugin registered, so it also covers the command once it is namespaced and any aliases of it
+    #[serde(default)]
+    pub rate_limits: HashMap<String, RateLimit>,
+    // Roles, who has them, and which commands need them
+    #[serde(default)]
+    pub permissions: Permissions,
+    // Alias -> the command it stands for, such as "r" -> "roll"
+    // Aliases and macros are left out, with a warning in the log, while a command has the same name or the one they run isn't registered
+    #[serde(default)]
+    pub aliases: HashMap<String, String>,
+    // Macro name -> the command it runs with some of the options filled in
+    #[serde(default)]
+    pub macros: HashMap<String, Macro>,
+}
+
+fn default_command_prefix() -> String {
+    "!".to_string()
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum ConflictPolicy {
+    #[default]
+    FirstWins, // The component that registered the command first keeps it, the others lose it
+    Namespace, // Every conflicting command is renamed to component:name, after the component that registered it
 }
 
 #[derive(Serialize, Deserialize)]
@@ -21,6 +76,46 @@ pub struct ComponentConstructor {
     pub name: String,
     pub type_: u8,
     pub key: String,
+    // How long a sniffer gets to return a packet, in milliseconds
+    #[serde(default = "default_sniffer_timeout")]
+    pub sniffer_timeout: u64,
+    // Whether a packet skips a sniffer that timed out instead of being dropped
+    #[serde(default = "default_fail_open")]
+    pub fail_open: bool,
+    // How often the component is pinged, in milliseconds
+    // Only components that list the heartbeat capability in their hello are pinged
+    #[serde(default = "default_heartbeat_interval")]
+    pub heartbeat_interval: u64,
+    // How many pings in a row can go unanswered before the component is considered dead
+    #[serde(default = "default_missed_heartbeats")]
+    pub missed_heartbeats: u32,
+    // What to do with a dead component
+    #[serde(default)]
+    pub on_dead: DeadPolicy,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum DeadPolicy {
+    #[default]
+    Reconnect, // Restart it, or wait for it to connect again if it is a network component
+    Remove, // Take it out of the components like it was removed from the menu
+}
+
+pub fn default_sniffer_timeout() -> u64 {
+    5000
+}
+
+pub fn default_fail_open() -> bool {
+    true
+}
+
+pub fn default_heartbeat_interval() -> u64 {
+    30000
+}
+
+pub fn default_missed_heartbeats() -> u32 {
+    3
 }
 
 impl Config {
@@ -30,6 +125,17 @@ impl Config {
             port: 0,
             host: "".to_string(),
             components: Vec::new(),
+            allow_plaintext_keys: false,
+            tls: None,
+            #[cfg(unix)]
+            socket: None,
+            command_conflicts: ConflictPolicy::default(),
+            command_overrides: HashMap::new(),
+            command_prefix: default_command_prefix(),
+            rate_limits: HashMap::new(),
+            permissions: Permissions::default(),
+            aliases: HashMap::new(),
+            macros: HashMap::new(),
         }
     }
     pub async fn save(&self) {
@@ -46,8 +152,37 @@ impl Config {
         let mut contents = String::new();
         file.read_to_string(&mut contents).await.unwrap();
         let config: Config = serde_json::from_str(&contents).unwrap();
+        if let Err(e) = config.validate() {
+            panic!("config.json is invalid: {}", e);
+        }
         Some(config)
     }
+
+    /// Checks the values serde can't
+    /// # Returns
+    /// * What is wrong with the config, if anything is
+    pub fn validate(&self) -> Result<(), String> {
+        // Every message would be parsed as a command otherwise
+        if self.command_prefix.trim().is_empty() {
+            return Err("command_prefix can't be empty".to_string());
+        }
+        // ":" is kept for namespacing, so an alias could pass itself off as another plugin's command
+        for name in self.aliases.keys().chain(self.macros.keys()) {
+            if name.is_empty() || name.contains(char::is_whitespace) || name.contains(':') {
+                return Err(format!("\"{}\" is not a valid alias or macro name", name));
+            }
+        }
+        for component in self.components.iter() {
+            // A component would be pinged nonstop, or be dead before it was ever pinged
+            if component.heartbeat_interval == 0 {
+                return Err(format!("{} has a heartbeat_interval of 0", component.name));
+            }
+            if component.missed_heartbeats == 0 {
+                return Err(format!("{} has a missed_heartbeats of 0", component.name));
+            }
+        }
+        Ok(())
+    }
 }
 
 impl Clone for Config {
@@ -57,6 +192,17 @@ impl Clone for Config {
             port: self.port,
             host: self.host.clone(),
             components: self.components.clone(),
+            allow_plaintext_keys: self.allow_plaintext_keys,
+            tls: self.tls.clone(),
+            #[cfg(unix)]
+            socket: self.socket.clone(),
+            command_conflicts: self.command_conflicts,
+            command_overrides: self.command_overrides.clone(),
+            command_prefix: self.command_prefix.clone(),
+            rate_limits: self.rate_limits.clone(),
+            permissions: self.permissions.clone(),
+            aliases: self.aliases.clone(),
+            macros: self.macros.clone(),
         }
     }
 }
@@ -69,6 +215,11 @@ impl Clone for ComponentConstructor {
             name: self.name.clone(),
             type_: self.type_,
             key: self.key.clone(),
+            sniffer_timeout: self.sniffer_timeout,
+            fail_open: self.fail_open,
+            heartbeat_interval: self.heartbeat_interval,
+            missed_heartbeats: self.missed_heartbeats,
+            on_dead: self.on_dead,
         }
     }
 }
@@ -1,6 +1,6 @@
 pub static SPLASH_SCREEN: &str = "                                                          `7wy$AOBBB@@@Df^`                         \n                                                        ?BB@@@@@@@@@@@@@@@ga7:                      \n              ```:,                                     X@@@@@@@@@@@@@@@@@@@@@X~                    \n          `|hQ@@@@@:                                   x@@@@@@@@@@@@@@@@@@@@@@@@p*.                 \n    \";cI2O@@@@@@@@@8:                          `+*<?IqB@@@@@@@@@@@@@@@@@@@@@@@@@@@@QA1r:\"           \n ,mB@@@@@@@@@@@@@@@@X                       .tpB@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@B$7,       \n|@@@@@@@@@@@@@@@@@@@@\"                    `|B@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@*      \nvy8dw9@@@@@@@@@@@@@@@Q:                  `M@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@Q;     \n      $@?;tHB@@@@@@@@@B*`               \"k@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@<    \n      m^?^;\"yg@@@@@@@@@@1             `v@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@X\"  \n      I   \"=\\`R@@@@@@@@@@;          ,wB@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@N  \n      1       `#@@@@@@@@@@9?\"`  \";IO@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@\" \n      t        .H@@@@@@@@@@@@@BB@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@; \n      }         `N@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@: \n      }          `p@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@` \n      ;?           *%@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@BR@@@@@@@@@@@@@B  \n       :\"            ~yB@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@U?`?@@@@@@@@@@@@@B` \n                        ,iXDB@@@@@@B#qy7^,1B@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@BI,   ;@@@@@@@@@@@@@@^ \n                                           `\\N@@@@@@@@@@@@@@@@@@@@@@@@@@Bpy=.      I@@@@@@@@@@@@@@B.\n                                              Q@@@@@@r/}}1i+?|iiii|?r;,`           \"B@@@@@@@@@@@@B@,\n                                              ?@@@@@@                               :B@@@@@@@@@@@GH \n                                              `d@@@@@,                               ,B@@@@@@@@@@>; \n                                               ;@@@@@=                                r@@@@@@@@@@r  \n                                                Q@@@@y                                ^@@@@qp@@@@I  \n                                                X@@@@N`                               ;@@@@Dr@@@@B` \n                                                l@@@@@<                               \"@@@@B G@@@@i \n                                                +@@@@@R                                B@@@@`\"@@@@B`\n                                                |@@@@@@~                               @@@@@* A@@@@R\n                                                I@@@@@@.                              ,@@@@B~ A@@@@l\n                                                ;@@@@@@                               j@@@#\"  $@@@h \n                                                 k@@@@N                               B@@R`   y@@@. \n                                                 ?@@R@R                              :@@q.    z@@t  \n                                                 *@B*@g                             `R@D`     w@N`  \n                                                 i@W;@B                             }@B.      8@7   \n                                                 w@$;@@                            :@@:      `@@`   \n                                                 B@Xy@@:                          :B@|       <@q    \n                                                f@@D@@@:                         +@@j       `R@1    \n                                               :@@@@@@B.                    `;|yB@@v`       W@@?    \n                                            `:FB@@@@BBW^                    :GpDWgp      `:$@@H`    \n                                           ~Q@@@@@#i                                   `D@@@@@t     \n\n\n                                        ####################                                        \n                                        ##### CamelBot #####                                        \n                                        ####################                                        \n";
 pub static SMOL_CAMEL: &str = "     _                \n .--' |               \n/___^ |     .--.      \n    ) |    /    \\     \n   /  |  /`      '.   \n  |   '-'    /     \\  \n  \\         |      |\\ \n   \\    /   \\      /\\|\n    \\  /'----`\\   /   \n    |||       \\ |    \n    ((|        ((|    \n    |||        |||    \n   //_(       //_(    ";
-pub static _HACKER_MESSAGES: &'static [&'static str] = &[
+pub static _HACKER_MESSAGES: &[&str] = &[
     "Hacking the mainframe",
     "Encrypting the files",
     "Compiling the code injectors",
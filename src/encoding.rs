@@ -0,0 +1,101 @@
+// jkcoxson
+// Turns packets into bytes for components, and back again
+
+// Everything inside the core is JSON, but a component can ask for MessagePack or CBOR when it switches framing
+// Binary encodings need length framing, since a newline could show up anywhere in them
+
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::sync::{Arc, OnceLock};
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum Encoding {
+    #[default]
+    Json,
+    Msgpack,
+    Cbor,
+}
+
+impl Encoding {
+    /// The capability a component has to list in its hello to use the encoding, None for JSON
+    pub fn capability(&self) -> Option<&'static str> {
+        match self {
+            Encoding::Json => None,
+            Encoding::Msgpack => Some("msgpack"),
+            Encoding::Cbor => Some("cbor"),
+        }
+    }
+
+    /// Decodes a packet sent by a component into JSON text
+    pub fn decode(&self, bytes: &[u8]) -> Result<String, String> {
+        match self {
+            Encoding::Json => Ok(String::from_utf8_lossy(bytes).to_string()),
+            Encoding::Msgpack => rmp_serde::from_slice::<Value>(bytes)
+                .map(|value| value.to_string())
+                .map_err(|e| e.to_string()),
+            Encoding::Cbor => ciborium::from_reader::<Value, _>(bytes)
+                .map(|value| value.to_string())
+                .map_err(|e| e.to_string()),
+        }
+    }
+}
+
+/// A packet on its way to one or more components
+/// Cloning is cheap, and each encoding is only worked out once no matter how many components get the packet
+pub struct Payload(Arc<Encoded>);
+
+struct Encoded {
+    text: String,
+    json: OnceLock<Result<Vec<u8>, String>>,
+    msgpack: OnceLock<Result<Vec<u8>, String>>,
+    cbor: OnceLock<Result<Vec<u8>, String>>,
+}
+
+impl Payload {
+    pub fn new(text: String) -> Payload {
+        Payload(Arc::new(Encoded {
+            text,
+            json: OnceLock::new(),
+            msgpack: OnceLock::new(),
+            cbor: OnceLock::new(),
+        }))
+    }
+
+    /// The packet as the core sees it
+    pub fn as_str(&self) -> &str {
+        &self.0.text
+    }
+
+    /// The packet as a component wants it
+    /// # Returns
+    /// * The bytes to send, or why the packet couldn't be encoded
+    pub fn encoded(&self, encoding: Encoding) -> &Result<Vec<u8>, String> {
+        let encoded = &self.0;
+        match encoding {
+            Encoding::Json => encoded
+                .json
+                .get_or_init(|| Ok(encoded.text.as_bytes().to_vec())),
+            Encoding::Msgpack => encoded.msgpack.get_or_init(|| {
+                let value = wire_value(&encoded.text)?;
+                rmp_serde::to_vec_named(&value).map_err(|e| e.to_string())
+            }),
+            Encoding::Cbor => encoded.cbor.get_or_init(|| {
+                let value = wire_value(&encoded.text)?;
+                let mut bytes = vec![];
+                ciborium::into_writer(&value, &mut bytes).map_err(|e| e.to_string())?;
+                Ok(bytes)
+            }),
+        }
+    }
+}
+
+impl Clone for Payload {
+    fn clone(&self) -> Payload {
+        Payload(self.0.clone())
+    }
+}
+
+fn wire_value(text: &str) -> Result<Value, String> {
+    serde_json::from_str(text).map_err(|e| e.to_string())
+}
@@ -0,0 +1,273 @@
+// jkcoxson
+// Splits the stream to and from a component into packets
+
+// Components start out sending one JSON packet per line, which is easy for simple scripts
+// A component can switch to length prefixed frames by sending {"type": "framing", "mode": "length"}
+// The core answers with the same packet as a line, and everything after that is frames in both directions
+// A frame is a big endian u32 length followed by that many bytes of JSON, or MessagePack or CBOR if the component asked for it
+
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+use crate::{
+    component::{ComponentRead, ComponentWrite},
+    encoding::{Encoding, Payload},
+};
+
+// The biggest packet a component can send, so a broken component can't eat all the memory
+pub const MAX_PACKET: usize = 64 * 1024 * 1024;
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Framing {
+    Lines,  // One packet per line
+    Length, // A u32 length and then the packet
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct FramingPacket {
+    #[serde(rename = "type", alias = "type_", default)]
+    pub type_: String,
+    pub mode: Framing,
+    #[serde(default)]
+    pub encoding: Encoding,
+}
+
+/// Reads packets from a component
+/// Partial packets are kept between reads, so a read can be cancelled without losing anything
+pub struct FramedReader<R> {
+    inner: R,
+    buffer: Vec<u8>,
+    framing: Framing,
+    encoding: Encoding,
+}
+
+/// Writes packets to a component
+pub struct FramedWriter<W> {
+    inner: W,
+    framing: Framing,
+    encoding: Encoding,
+}
+
+impl<R: AsyncRead + Unpin + Send> FramedReader<R> {
+    pub fn new(inner: R) -> FramedReader<R> {
+        FramedReader {
+            inner,
+            buffer: Vec::new(),
+            framing: Framing::Lines,
+            encoding: Encoding::Json,
+        }
+    }
+
+    /// Takes the next whole packet out of the buffer
+    /// # Returns
+    /// * The packet, None if there isn't a whole one yet, or an error if the component broke the rules
+    fn next_packet(&mut self) -> Result<Option<Vec<u8>>, ()> {
+        match self.framing {
+            Framing::Lines => loop {
+                let end = match self.buffer.iter().position(|b| *b == b'\n') {
+                    Some(end) => end,
+                    None if self.buffer.len() > MAX_PACKET => return Err(()),
+                    None => return Ok(None),
+                };
+                let line: Vec<u8> = self.buffer.drain(..=end).collect();
+                // Blank lines are just skipped
+                if !line.trim_ascii().is_empty() {
+                    return Ok(Some(line));
+                }
+            },
+            Framing::Length => {
+                if self.buffer.len() < 4 {
+                    return Ok(None);
+                }
+                let length = u32::from_be_bytes([
+                    self.buffer[0],
+                    self.buffer[1],
+                    self.buffer[2],
+                    self.buffer[3],
+                ]) as usize;
+                if length > MAX_PACKET {
+                    return Err(());
+                }
+                if self.buffer.len() < 4 + length {
+                    return Ok(None);
+                }
+                Ok(Some(self.buffer.drain(..4 + length).skip(4).collect()))
+            }
+        }
+    }
+}
+
+impl<W: AsyncWrite + Unpin + Send> FramedWriter<W> {
+    pub fn new(inner: W) -> FramedWriter<W> {
+        FramedWriter {
+            inner,
+            framing: Framing::Lines,
+            encoding: Encoding::Json,
+        }
+    }
+}
+
+#[async_trait]
+impl<R: AsyncRead + Unpin + Send> ComponentRead for FramedReader<R> {
+    async fn read(&mut self) -> String {
+        loop {
+            match self.next_packet() {
+                Ok(Some(packet)) => match self.encoding.decode(&packet) {
+                    // An empty read means the component exited, so empty frames are skipped like blank lines
+                    Ok(packet) if packet.trim().is_empty() => continue,
+                    Ok(packet) => return packet.trim().to_string(),
+                    // Not worth hanging up over, the packet is just skipped
+                    Err(_) => continue,
+                },
+                Ok(None) => {}
+                Err(_) => {
+                    // We dead bro
+                    return "".to_string();
+                }
+            }
+            self.buffer.reserve(8192);
+            match self.inner.read_buf(&mut self.buffer).await {
+                Ok(0) | Err(_) => return "".to_string(),
+                Ok(_) => {}
+            }
+        }
+    }
+
+    fn set_framing(&mut self, framing: Framing, encoding: Encoding) {
+        self.framing = framing;
+        self.encoding = encoding;
+    }
+}
+
+#[async_trait]
+impl<W: AsyncWrite + Unpin + Send> ComponentWrite for FramedWriter<W> {
+    async fn write(&mut self, msg: String) {
+        self.write_payload(&Payload::new(msg)).await;
+    }
+
+    async fn write_payload(&mut self, payload: &Payload) {
+        let msg = match payload.encoded(self.encoding) {
+            Ok(msg) => msg,
+            Err(_) => {
+                return;
+            }
+        };
+        let msg = match self.framing {
+            Framing::Lines => [msg.as_slice(), b"\n"].concat(),
+            Framing::Length => {
+                let mut frame = (msg.len() as u32).to_be_bytes().to_vec();
+                frame.extend_from_slice(msg);
+                frame
+            }
+        };
+        match self.inner.write_all(&msg).await {
+            Ok(_) => {}
+            Err(_) => {
+                return;
+            }
+        }
+        let _ = self.inner.flush().await;
+    }
+
+    fn set_framing(&mut self, framing: Framing, encoding: Encoding) {
+        self.framing = framing;
+        self.encoding = encoding;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn buffered(framing: Framing, buffer: &[u8]) -> FramedReader<tokio::io::Empty> {
+        let mut reader = FramedReader::new(tokio::io::empty());
+        reader.set_framing(framing, Encoding::Json);
+        reader.buffer.extend_from_slice(buffer);
+        reader
+    }
+
+    fn frame(packet: &[u8]) -> Vec<u8> {
+        let mut frame = (packet.len() as u32).to_be_bytes().to_vec();
+        frame.extend_from_slice(packet);
+        frame
+    }
+
+    #[test]
+    fn partial_line_waits_for_newline() {
+        let mut reader = buffered(Framing::Lines, b"{\"type\":");
+        assert_eq!(reader.next_packet(), Ok(None));
+        reader.buffer.extend_from_slice(b"\"event\"}\n\n  \n{}");
+        assert_eq!(
+            reader.next_packet(),
+            Ok(Some(b"{\"type\":\"event\"}\n".to_vec()))
+        );
+        // Blank lines are skipped and the last packet isn't finished yet
+        assert_eq!(reader.next_packet(), Ok(None));
+        assert_eq!(reader.buffer, b"{}");
+    }
+
+    #[test]
+    fn partial_frame_waits_for_the_rest() {
+        let whole = frame(b"{\"type\":\"event\"}");
+        let mut reader = buffered(Framing::Length, &whole[..2]);
+        assert_eq!(reader.next_packet(), Ok(None));
+        reader.buffer.extend_from_slice(&whole[2..8]);
+        assert_eq!(reader.next_packet(), Ok(None));
+        reader.buffer.extend_from_slice(&whole[8..]);
+        reader.buffer.extend_from_slice(&frame(b"{}"));
+        assert_eq!(
+            reader.next_packet(),
+            Ok(Some(b"{\"type\":\"event\"}".to_vec()))
+        );
+        assert_eq!(reader.next_packet(), Ok(Some(b"{}".to_vec())));
+        assert_eq!(reader.next_packet(), Ok(None));
+    }
+
+    #[test]
+    fn frame_over_max_packet_is_rejected() {
+        let length = (MAX_PACKET as u32 + 1).to_be_bytes();
+        let mut reader = buffered(Framing::Length, &length);
+        assert_eq!(reader.next_packet(), Err(()));
+
+        let length = (MAX_PACKET as u32).to_be_bytes();
+        let mut reader = buffered(Framing::Length, &length);
+        assert_eq!(reader.next_packet(), Ok(None));
+    }
+
+    #[test]
+    fn line_over_max_packet_is_rejected() {
+        let mut reader = buffered(Framing::Lines, &vec![b'a'; MAX_PACKET + 1]);
+        assert_eq!(reader.next_packet(), Err(()));
+    }
+
+    #[test]
+    fn framing_switches_mid_buffer() {
+        let mut buffer = b"{\"type\":\"framing\",\"mode\":\"length\"}\n".to_vec();
+        buffer.extend_from_slice(&frame(b"{\"a\":1}\n"));
+        let mut reader = buffered(Framing::Lines, &buffer);
+        assert_eq!(
+            reader.next_packet(),
+            Ok(Some(
+                b"{\"type\":\"framing\",\"mode\":\"length\"}\n".to_vec()
+            ))
+        );
+        // The frame after it has a newline in it, which only length framing gets right
+        reader.set_framing(Framing::Length, Encoding::Json);
+        assert_eq!(reader.next_packet(), Ok(Some(b"{\"a\":1}\n".to_vec())));
+        assert_eq!(reader.next_packet(), Ok(None));
+    }
+
+    #[tokio::test]
+    async fn empty_frames_are_skipped() {
+        let mut buffer = frame(b"");
+        buffer.extend_from_slice(&frame(b" \n "));
+        buffer.extend_from_slice(&frame(b"{}"));
+        let mut reader = FramedReader::new(buffer.as_slice());
+        reader.set_framing(Framing::Length, Encoding::Json);
+        assert_eq!(reader.read().await, "{}");
+        // Only the end of the stream reads as empty
+        assert_eq!(reader.read().await, "");
+    }
+}
@@ -0,0 +1,113 @@
+// jkcoxson
+// The first thing a component says to the core
+
+// A component can start with a hello packet saying which version of the protocol it speaks and what it supports
+// The core answers with the version they will use and the capabilities they both have
+// Components that start talking without saying hello are from before hellos, and get none of the capabilities
+// New features should check for a capability, so that components that don't know about them are left alone
+
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+
+// The newest version of the protocol the core speaks, and the oldest it still understands
+pub const PROTOCOL_VERSION: u32 = 1;
+pub const MIN_PROTOCOL_VERSION: u32 = 1;
+
+// How long the core waits for a hello before deciding the component is from before hellos
+pub const HELLO_TIMEOUT: Duration = Duration::from_secs(2);
+
+// Everything the core can do that a component might not know about
+pub const CAPABILITIES: &[&str] = &[
+    "sniffer",       // Sniffer packets with IDs that have to be handed back
+    "rpc",           // Request and response packets
+    "framing",       // Length prefixed frames
+    "msgpack",       // MessagePack frames
+    "cbor",          // CBOR frames
+    "commands_diff", // Commands diff packets after the first list
+    "commands_ack",  // Commands ack packets
+    "heartbeat",     // Ping packets that have to be answered with a pong
+];
+
+#[derive(Deserialize)]
+pub struct HelloPacket {
+    pub version: u32,
+    // What the component thinks it is, checked against the config
+    #[serde(default)]
+    pub component_type: Option<u8>,
+    #[serde(default)]
+    pub capabilities: Vec<String>,
+}
+
+#[derive(Serialize)]
+pub struct HelloReplyPacket {
+    #[serde(rename = "type")]
+    pub type_: String,
+    pub version: u32,
+    pub capabilities: Vec<String>,
+}
+
+#[derive(Serialize)]
+pub struct HelloErrorPacket {
+    #[serde(rename = "type")]
+    pub type_: String,
+    pub message: String,
+    pub min_version: u32,
+    pub max_version: u32,
+}
+
+/// What the core and a component agreed on
+pub struct Capabilities {
+    pub version: u32, // 0 if the component never said hello
+    pub features: Vec<String>,
+}
+
+impl Capabilities {
+    /// The capabilities of a component from before hellos
+    pub fn legacy() -> Capabilities {
+        Capabilities {
+            version: 0,
+            features: vec![],
+        }
+    }
+
+    /// Whether the component said it supports a feature
+    pub fn has(&self, feature: &str) -> bool {
+        self.features.iter().any(|f| f == feature)
+    }
+}
+
+impl Clone for Capabilities {
+    fn clone(&self) -> Capabilities {
+        Capabilities {
+            version: self.version,
+            features: self.features.clone(),
+        }
+    }
+}
+
+impl HelloPacket {
+    /// Works out what the core and the component have in common
+    /// # Returns
+    /// * The capabilities, or the packet telling the component why it can't connect
+    pub fn negotiate(&self) -> Result<Capabilities, HelloErrorPacket> {
+        if self.version < MIN_PROTOCOL_VERSION || self.version > PROTOCOL_VERSION {
+            return Err(HelloErrorPacket {
+                type_: "hello_error".to_string(),
+                message: format!(
+                    "protocol version {} is not supported, this core speaks {} to {}",
+                    self.version, MIN_PROTOCOL_VERSION, PROTOCOL_VERSION
+                ),
+                min_version: MIN_PROTOCOL_VERSION,
+                max_version: PROTOCOL_VERSION,
+            });
+        }
+        Ok(Capabilities {
+            version: self.version,
+            features: CAPABILITIES
+                .iter()
+                .filter(|c| self.capabilities.iter().any(|f| f == *c))
+                .map(|c| c.to_string())
+                .collect(),
+        })
+    }
+}
@@ -1,33 +1,121 @@
 // jkcoxson
 
 use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::{commands::CommandStructure, packet::SnifferIntent, subscriptions};
+
+// The newest version of the intents packet the core understands
+pub const INTENTS_VERSION: u32 = 1;
 
 #[derive(Serialize, Deserialize)]
 pub struct Intent {
+    // Components from before intents were versioned are version 1
+    #[serde(default = "default_version")]
+    pub version: u32,
+    // The components to receive events from, empty for all of them
+    #[serde(default)]
     pub components: Vec<String>,
+    // Whether the component accepts send packets from other components
+    #[serde(default = "default_messages")]
     pub messages: bool,
     pub events: Vec<String>,
-    pub commands: Vec<Command>,
+    pub commands: Vec<CommandStructure>,
+    // Only used by sniffers
+    #[serde(default)]
+    pub sniffer: SnifferIntent,
 }
 
-#[derive(Serialize, Deserialize)]
-pub struct Command {
-    pub name: String,
-    pub description: String,
-    pub parameters: Vec<Parameter>,
+/// Why an intents packet was rejected
+pub struct IntentError {
+    pub field: String,
+    pub message: String,
 }
 
-#[derive(Serialize, Deserialize)]
-pub struct Parameter {
-    pub name: String,
+#[derive(Serialize)]
+pub struct IntentErrorPacket {
+    #[serde(rename = "type")]
     pub type_: String,
-    pub description: String,
-    pub required: bool,
-    pub options: Vec<String>,
+    pub field: String,
+    pub message: String,
+}
+
+fn default_version() -> u32 {
+    1
+}
+
+fn default_messages() -> bool {
+    true
 }
 
 impl Intent {
-    pub fn new(json: String) -> Intent {
-        serde_json::from_str(&json).unwrap()
+    /// Parses and validates an intents packet
+    /// # Arguments
+    /// * `json` - The intents packet
+    /// # Returns
+    /// * The intents, or the field that was wrong with them
+    pub fn new(json: Value) -> Result<Intent, IntentError> {
+        let intent: Intent = match serde_path_to_error::deserialize(json) {
+            Ok(intent) => intent,
+            Err(e) => {
+                return Err(IntentError {
+                    field: e.path().to_string(),
+                    message: e.into_inner().to_string(),
+                })
+            }
+        };
+
+        if intent.version > INTENTS_VERSION {
+            return Err(IntentError {
+                field: "version".to_string(),
+                message: format!(
+                    "version {} is newer than this core supports ({})",
+                    intent.version, INTENTS_VERSION
+                ),
+            });
+        }
+        for (i, event) in intent.events.iter().enumerate() {
+            if event.is_empty() {
+                return Err(IntentError {
+                    field: format!("events[{}]", i),
+                    message: "event can't be empty".to_string(),
+                });
+            }
+            if let Err(message) = subscriptions::validate(event) {
+                return Err(IntentError {
+                    field: format!("events[{}]", i),
+                    message,
+                });
+            }
+        }
+        for (i, event) in intent.sniffer.events.iter().enumerate() {
+            if let Err(message) = subscriptions::validate(event) {
+                return Err(IntentError {
+                    field: format!("sniffer.events[{}]", i),
+                    message,
+                });
+            }
+        }
+        for (i, command) in intent.commands.iter().enumerate() {
+            // ":" is kept for namespacing, so nobody can register a name that looks like another plugin's
+            if command.name.is_empty()
+                || command.name.contains(char::is_whitespace)
+                || command.name.contains(':')
+            {
+                return Err(IntentError {
+                    field: format!("commands[{}].name", i),
+                    message: format!("\"{}\" is not a valid command name", command.name),
+                });
+            }
+            for (j, option) in command.options.iter().enumerate() {
+                if option.name.is_empty() {
+                    return Err(IntentError {
+                        field: format!("commands[{}].options[{}].name", i, j),
+                        message: "option name can't be empty".to_string(),
+                    });
+                }
+            }
+        }
+        Ok(intent)
     }
 }
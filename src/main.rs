@@ -2,24 +2,30 @@
 // jkcoxson
 // All hail camels
 
-use commands::Command;
+use commands::CommandRegistry;
 use config::ComponentConstructor;
-use std::{collections::HashMap, sync::Arc};
-use tokio::{
-    fs::File,
-    io::{AsyncReadExt, AsyncWriteExt},
-    net::TcpStream,
-    sync::{mpsc::UnboundedSender, Mutex},
-};
+use std::{collections::HashMap, sync::Arc, time::Duration};
+use tokio::sync::{mpsc::UnboundedSender, Mutex};
 
-use crate::{component::Component, packet::Packet};
+use crate::{component::Component, packet::Packet, tls::Connection};
 
+mod auth;
 mod commands;
 mod component;
 mod config;
 mod constants;
+mod encoding;
+mod framing;
+mod handshake;
+mod intents;
 mod packet;
+mod parser;
+mod permissions;
+mod subscriptions;
+mod tls;
 mod ui;
+#[cfg(unix)]
+mod unix;
 
 #[tokio::main]
 async fn main() {
@@ -43,14 +49,21 @@ async fn main() {
         Arc::new(Mutex::new(HashMap::new()));
 
     // Command Arc
-    let file = File::open("./command_cache.json").await;
-    let command_arc = match file {
-        Ok(_) => Arc::new(Mutex::new(commands::load_cache().await)),
-        Err(_) => Arc::new(Mutex::new(Vec::new())),
+    let command_arc = match commands::load_cache(&config).await {
+        Ok(Some(registry)) => Arc::new(Mutex::new(registry)),
+        Ok(None) => Arc::new(Mutex::new(CommandRegistry::new(&config))),
+        Err(e) => {
+            logger.error(&format!(
+                "Failed to load the command cache, starting without it: {}",
+                e
+            ));
+            Arc::new(Mutex::new(CommandRegistry::new(&config)))
+        }
     };
 
     // Network Arc
-    let network_arc: Arc<Mutex<HashMap<String, UnboundedSender<TcpStream>>>> =
+    // Component name -> where to hand its connection to once it connects
+    let network_arc: Arc<Mutex<HashMap<String, UnboundedSender<Connection>>>> =
         Arc::new(Mutex::new(HashMap::new()));
 
     // Start componenents
@@ -69,9 +82,24 @@ async fn main() {
     if config.tcp {
         let host = config.host.clone();
         let port = config.port;
+        let allow_plaintext = config.allow_plaintext_keys;
+        let tls_config = config.tls.clone();
         let logger = logger.clone("core".to_string());
+        let component_arc = component_arc.clone();
         let network_arc = network_arc.clone();
         tokio::spawn(async move {
+            // Load the certificates before listening, so a broken TLS setup doesn't fall back to plain TCP
+            let tls = match tls_config.as_ref().map(tls::Tls::new) {
+                Some(Ok(tls)) => Some(Arc::new(tls)),
+                Some(Err(e)) => {
+                    logger.error(&format!(
+                        "Failed to set up TLS, not starting the TCP listener: {}",
+                        e
+                    ));
+                    return;
+                }
+                None => None,
+            };
             // Start TCP listener
             let listener = match tokio::net::TcpListener::bind(format!("{}:{}", &host, &port)).await
             {
@@ -84,31 +112,95 @@ async fn main() {
             logger.info(&format!("Listening on {}:{}", host, port));
             loop {
                 // Wait for connection
-                let (mut socket, _) = listener.accept().await.unwrap();
-                socket.write(b"").await.unwrap();
-                // Read the first line from socket
-                let mut key = "".to_string();
-                loop {
-                    let mut buffer = [1];
-                    let _ = socket.read(&mut buffer).await.unwrap();
-                    let char = buffer[0] as char;
-                    if char == '\n' {
-                        break;
-                    } else {
-                        key.push(char);
+                let (socket, address) = match listener.accept().await {
+                    Ok(connection) => connection,
+                    Err(e) => {
+                        logger.warn(&format!("Failed to accept a TCP connection: {}", e));
+                        continue;
                     }
-                }
+                };
+                // A slow component shouldn't hold up everyone else
+                let logger = logger.clone("core".to_string());
+                let component_arc = component_arc.clone();
+                let network_arc = network_arc.clone();
+                let tls = tls.clone();
+                tokio::spawn(async move {
+                    let (connection, certificate) = match &tls {
+                        Some(tls) => match tls.accept(socket).await {
+                            Ok(accepted) => accepted,
+                            Err(reason) => {
+                                logger.warn(&format!(
+                                    "Rejected a connection from {}: {}",
+                                    address, reason
+                                ));
+                                return;
+                            }
+                        },
+                        None => (Connection::Plain(socket), None),
+                    };
+                    if let Err(reason) = auth::hand_off(
+                        connection,
+                        certificate,
+                        &component_arc,
+                        &network_arc,
+                        allow_plaintext,
+                    )
+                    .await
+                    {
+                        logger.warn(&format!(
+                            "Rejected a connection from {}: {}",
+                            address, reason
+                        ));
+                    }
+                });
+            }
+        });
+    }
 
-                // Compare key to waiting components
-                let lock = network_arc.lock().await;
-                match lock.get(&key) {
-                    Some(sender) => {
-                        let _ = sender.send(socket).unwrap();
+    #[cfg(unix)]
+    if let Some(socket_config) = config.socket.clone() {
+        let allow_plaintext = config.allow_plaintext_keys;
+        let logger = logger.clone("core".to_string());
+        let component_arc = component_arc.clone();
+        let network_arc = network_arc.clone();
+        tokio::spawn(async move {
+            // Start Unix socket listener
+            let socket = match unix::Socket::bind(&socket_config) {
+                Ok(socket) => socket,
+                Err(e) => {
+                    logger.error(&format!("Failed to start the Unix socket listener: {}", e));
+                    return;
+                }
+            };
+            logger.info(&format!("Listening on {}", socket_config.path));
+            loop {
+                // Wait for connection
+                let (connection, uid, peer) = match socket.accept().await {
+                    Ok(connection) => connection,
+                    Err(e) => {
+                        logger.warn(&format!("Failed to accept a Unix socket connection: {}", e));
+                        continue;
                     }
-                    None => {
-                        logger.warn(&format!("Received connection with unknown key {}", key));
+                };
+                let logger = logger.clone("core".to_string());
+                let component_arc = component_arc.clone();
+                let network_arc = network_arc.clone();
+                tokio::spawn(async move {
+                    if let Err(reason) = auth::hand_off(
+                        connection,
+                        peer,
+                        &component_arc,
+                        &network_arc,
+                        allow_plaintext,
+                    )
+                    .await
+                    {
+                        logger.warn(&format!(
+                            "Rejected a Unix socket connection from user {}: {}",
+                            uid, reason
+                        ));
                     }
-                }
+                });
             }
         });
     }
@@ -128,18 +220,31 @@ pub async fn create_component(
     i: &ComponentConstructor,
     logger: ui::Logger,
     component_arc: Arc<Mutex<HashMap<String, Component>>>,
-    command_arc: Arc<Mutex<Vec<Command>>>,
-    network_arc: Arc<Mutex<HashMap<String, UnboundedSender<TcpStream>>>>,
+    command_arc: Arc<Mutex<CommandRegistry>>,
+    network_arc: Arc<Mutex<HashMap<String, UnboundedSender<Connection>>>>,
     config: config::Config,
 ) {
-    if i.network && !config.tcp {
-        logger.warn(format!("Interface {} is configured for network mode, but TCP mode is not enabled. It will not be loaded.", i.name).as_str());
+    #[cfg(unix)]
+    let listening = config.tcp || config.socket.is_some();
+    #[cfg(not(unix))]
+    let listening = config.tcp;
+    if i.network && !listening {
+        logger.warn(format!("Interface {} is configured for network mode, but neither TCP mode nor the Unix socket is enabled. It will not be loaded.", i.name).as_str());
         return;
     }
 
     // Create component
     let (tx, rx) = tokio::sync::mpsc::unbounded_channel();
-    let comp = component::Component::new(i.name.clone(), 0, i.key.clone(), tx);
+    let mut comp = component::Component::new(i.name.clone(), i.type_, i.key.clone(), tx);
+    comp.sniffer_timeout = Duration::from_millis(i.sniffer_timeout);
+    comp.fail_open = i.fail_open;
+    comp.heartbeat_interval = Duration::from_millis(i.heartbeat_interval);
+    comp.missed_heartbeats = i.missed_heartbeats;
+    comp.on_dead = i.on_dead;
+    // Components that connect by certificate or as a Unix socket peer don't need a key
+    if i.network {
+        comp.network = true;
+    }
 
     // Insert component into map
     component_arc.lock().await.insert(i.name.clone(), comp);
@@ -148,29 +253,20 @@ pub async fn create_component(
         if j.id == i.name {
             continue;
         }
-        match j.sender.send(Packet {
-            source: "".to_string(),
-            destination: "".to_string(),
-            event: "".to_string(),
-            data: "update".to_string(),
-            sniffers: vec![],
-        }) {
-            _ => {} // Don't care
-        }
+        // Don't care
+        let _ = j.sender.send(Packet::control("", "update"));
     }
 
     // Start component
-    let command = i.command.split(" ").collect::<Vec<&str>>()[0];
-    let args = i.command.split(" ").skip(1).collect::<Vec<&str>>();
+    let command = i.command.split(' ').collect::<Vec<&str>>()[0];
+    let args = i.command.split(' ').skip(1).collect::<Vec<&str>>();
     let args: Vec<String> = args.iter().map(|x| x.to_string()).collect();
-    let key = i.key.clone();
 
     component::Component::connect(
         i.name.clone(),
         logger.clone(i.name.clone()),
         command.to_string(),
         args,
-        key,
         component_arc,
         command_arc,
         network_arc,
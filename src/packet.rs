@@ -1,16 +1,69 @@
 // jkcoxson
 
+use serde_json::Value;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use crate::{encoding::Payload, subscriptions::matches};
+
+// IDs are handed out from here so that no two packets in flight share one
+static NEXT_ID: AtomicU64 = AtomicU64::new(1);
+
+/// Gets a fresh packet ID
+pub fn next_id() -> u64 {
+    NEXT_ID.fetch_add(1, Ordering::Relaxed)
+}
+
 pub struct Packet {
+    pub id: u64,
     pub source: String,
     pub destination: String,
     pub event: String,
-    pub data: String,
+    pub data: Payload, // The packet as the component will see it
     pub sniffers: Vec<String>,
 }
 
+impl Packet {
+    /// Creates a packet used by the core to control a component's thread
+    /// # Arguments
+    /// * `source` - The component that triggered the control packet
+    /// * `data` - The control message, such as "update", "kill" or "reload"
+    pub fn control(source: &str, data: &str) -> Packet {
+        Packet {
+            id: 0,
+            source: source.to_string(),
+            destination: "".to_string(),
+            event: "".to_string(),
+            data: Payload::new(data.to_string()),
+            sniffers: vec![],
+        }
+    }
+}
+
+/// Renames "type_" to "type" on packets from components written before the core stopped rewriting it
+/// Only the top of the packet is touched, the typed packets accept either name for everything else
+/// # Returns
+/// * Whether the packet was using the old name
+pub fn legacy_type(packet: &mut Value) -> bool {
+    let packet = match packet.as_object_mut() {
+        Some(packet) => packet,
+        None => return false,
+    };
+    if packet.contains_key("type") {
+        return false;
+    }
+    match packet.remove("type_") {
+        Some(type_) => {
+            packet.insert("type".to_string(), type_);
+            true
+        }
+        None => false,
+    }
+}
+
 impl Clone for Packet {
     fn clone(&self) -> Packet {
         Packet {
+            id: self.id,
             source: self.source.clone(),
             destination: self.destination.clone(),
             event: self.event.clone(),
@@ -20,12 +73,124 @@ impl Clone for Packet {
     }
 }
 
+/// Sent to components that do heartbeats, which answer with a pong with the same nonce
+#[derive(serde::Serialize)]
+pub struct PingPacket {
+    #[serde(rename = "type")]
+    pub type_: String,
+    pub nonce: Value,
+}
+
 #[derive(serde::Serialize, serde::Deserialize)]
 pub struct SnifferPacket {
+    #[serde(rename = "type", alias = "type_")]
     pub type_: String,
+    // No touchy, the core uses this to match the packet up with the one it sent out
+    #[serde(default)]
+    pub id: u64,
+    // Only for the sniffer to look at, the core routes the packet the way it sent it out
+    #[serde(default)]
     pub source: String,
+    #[serde(default)]
     pub destination: String,
+    #[serde(default)]
     pub event: String,
+    #[serde(default)]
     pub sniffers: Vec<String>,
-    pub packet: String,
+    // The packet being sniffed, null if the sniffer wants it dropped
+    #[serde(default)]
+    pub packet: Value,
+    // Set by the sniffer to drop the packet instead of passing it on
+    #[serde(default)]
+    pub drop: bool,
+}
+
+/// What a sniffer wants to sniff, sent in the "sniffer" field of its intents
+#[derive(serde::Serialize, serde::Deserialize, Default)]
+pub struct SnifferIntent {
+    // Sniffers with a higher priority sniff first, ties are broken by ID
+    #[serde(default)]
+    pub priority: i32,
+    // The events to sniff, wildcards such as "message.*" work here too
+    #[serde(default)]
+    pub events: Vec<String>,
+    // The destinations to sniff "send" packets for
+    #[serde(default)]
+    pub destinations: Vec<String>,
+}
+
+impl SnifferIntent {
+    /// Whether a packet with this event and destination should pass through the sniffer
+    /// A sniffer that hasn't narrowed anything down sniffs everything
+    pub fn wants(&self, event: &str, destination: &str) -> bool {
+        if self.events.is_empty() && self.destinations.is_empty() {
+            return true;
+        }
+        if destination.is_empty() {
+            self.events.iter().any(|e| matches(e, event))
+        } else {
+            self.destinations.iter().any(|d| d == destination)
+        }
+    }
+}
+
+impl Clone for SnifferIntent {
+    fn clone(&self) -> SnifferIntent {
+        SnifferIntent {
+            priority: self.priority,
+            events: self.events.clone(),
+            destinations: self.destinations.clone(),
+        }
+    }
+}
+
+/// A request from one component to another, expecting a response
+#[derive(serde::Serialize, serde::Deserialize)]
+pub struct RequestPacket {
+    #[serde(rename = "type", alias = "type_")]
+    pub type_: String,
+    pub id: u64, // Assigned by the core, the response must have the same one
+    pub source: String,
+    #[serde(default)]
+    pub timeout: u64, // How long the caller waits for the response in milliseconds, a later one is thrown away
+    pub data: Value,
+}
+
+/// The response to a request, either from the target or made up by the core when something went wrong
+#[derive(serde::Serialize, serde::Deserialize)]
+pub struct ResponsePacket {
+    #[serde(rename = "type", alias = "type_")]
+    pub type_: String,
+    pub id: u64,
+    pub source: String,
+    // Whatever the caller put in its request, so it can tell its responses apart
+    pub nonce: Value,
+    pub data: Value,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+impl ResponsePacket {
+    /// Creates a response from the core for a request that couldn't be answered
+    pub fn error(id: u64, target: &str, nonce: Value, error: &str) -> ResponsePacket {
+        ResponsePacket {
+            type_: "response".to_string(),
+            id,
+            source: target.to_string(),
+            nonce,
+            data: Value::Null,
+            error: Some(error.to_string()),
+        }
+    }
+}
+
+/// Sent back to a component when a packet it sent couldn't reach its target
+#[derive(serde::Serialize, serde::Deserialize)]
+pub struct DeliveryFailedPacket {
+    #[serde(rename = "type", alias = "type_")]
+    pub type_: String,
+    pub id: u64,      // The ID the core gave the original packet
+    pub nonce: Value, // Whatever nonce the component put on the original packet
+    pub target: String,
+    pub reason: String,
 }
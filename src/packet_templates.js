@@ -4,17 +4,97 @@ console.log("This file is not meant to be run");
 
 _ = [
     // All packets are formated as JSON objects
-    // Depending on the connection type, they will be piped through stdout or a TCP socket following '\n'
+    // Depending on the connection type, they will be piped through stdout, a TCP socket or the Unix socket following '\n', unless framing is switched
     // All packets must include a type, this will be used to determine how to parse the packet
+    // Packets are passed on exactly as they were sent, the core never rewrites the text inside of them
+    // Old components that send "type_" instead of "type" still work, but get a warning in the log
 
+    // Challenge packets
+    // TCP components get this from the core as soon as they connect, before anything else
+    // The answer is the HMAC-SHA256 of the nonce, keyed with the component's key from the config, in hex
+    // If the answer is wrong, the core sends an auth_error packet and hangs up
+    // Sending the key itself as the first line only works if allow_plaintext_keys is set in the config
+    // If the config has a tls section, TCP components connect with TLS first, except from this machine when loopback_plaintext is on
+    // The TLS handshake has to finish within 10 seconds, or the core hangs up
+    // Components that present a client certificate signed by client_ca, with a common name in client_names, skip the challenge
+    // If the config has a socket section, components on this machine can connect to its Unix socket instead, speaking the same packets as over TCP
+    // Components run by a user listed in the socket's peers skip the challenge, everyone else gets one
+    // Components that only connect as a peer or with a certificate can leave their key empty, as long as network is set in the config
+    {
+        type: "challenge",
+        nonce: "9f86d081884c7d659a2feaa0c55ad015a3bf4f1b2b0b822cd15d6c15b0f00a08",
+    },
+    {
+        type: "auth",
+        hmac: "5d5d139563c95b5967b9bd9a8c9b233a9dedb45072794cd232dc1b74832607d0",
+    },
+    {
+        type: "auth_error",
+        message: "answered the challenge with the wrong key",
+    },
+
+    // Hello packets
+    // These packets are the first thing a component sends, before anything else
+    // The core answers with the protocol version it will use and the capabilities both sides have
+    // New features are only used with components that list them, so a component should only list what it understands
+    // sniffer - sniffers without it aren't sent packets to sniff
+    // rpc - requests to components without it fail with "target does not support requests"
+    // framing, msgpack, cbor - framing packets asking for something that wasn't listed are ignored
+    // commands_diff - interfaces without it get the whole commands packet instead of diffs
+    // commands_ack - commands ack packets from interfaces without it are ignored
+    // heartbeat - components without it aren't pinged
+    // Components that don't say hello within 2 seconds are treated as old components with no capabilities
+    {
+        type: "hello",
+        version: 1, // The protocol version the component speaks
+        component_type: 1, // Optional, 0 - Interface, 1 - Plugin, 2 - Sniffer, checked against the config
+        capabilities: ["sniffer", "rpc", "framing", "msgpack", "cbor", "commands_diff", "commands_ack", "heartbeat"],
+    },
+    // If the core doesn't speak the version, it sends this and hangs up
+    // A network component can try again with another version, a component the core started isn't restarted
+    {
+        type: "hello_error",
+        message: "protocol version 2 is not supported, this core speaks 1 to 1",
+        min_version: 1,
+        max_version: 1,
+    },
+
+    // Ping packets
+    // These are sent from the core to components that listed the heartbeat capability in their hello
+    // The component answers with a pong with the same nonce, and one that misses too many in a row is considered dead
+    // How often pings go out, how many can be missed and whether a dead component is reconnected or removed is in the config
+    {
+        type: "ping",
+        nonce: 42,
+    },
+    {
+        type: "pong",
+        nonce: 42,
+    },
+    // A component can send a ping to the core too, and it will answer with a pong
+
+    // Framing packets
+    // Newline separated JSON is fine for small scripts, but big packets like images are slow to split up by lines
+    // A component can switch to frames: a big endian u32 length followed by that many bytes of JSON
+    // The core answers with the same packet, still as a line, and everything after it is frames in both directions
+    // Packets can be up to 64 MiB either way
+    // Frames can also hold MessagePack or CBOR instead of JSON, which is quicker for busy interfaces
+    // The packets are the same, just encoded differently, and binary encodings only work with length framing
+    {
+        type: "framing",
+        mode: "length", // "length" or "lines"
+        encoding: "msgpack", // Optional, "json", "msgpack" or "cbor"
+    },
 
     // Event packets
     // These packets are sent when an event occurs that needs to be broadcasted
     // Only components that have requested the event type will receive the packet
-    // For example, if I have a spam detection plugin, I will request the 'message' event
+    // For example, if I have a spam detection plugin, I will request the 'message.create' event
+    // Events are named with dots, and can be requested with wildcards such as 'message.*' or '*'
+    // A * anywhere else, like 'mess*', gets the intents rejected
     {
         type: "event",
-        event: "message",
+        event: "message.create",
         data: {
             author: "jkcoxson",
             message: "Hello, world!",
@@ -26,39 +106,223 @@ _ = [
     {
         type: "send",
         target: "my_fancy_component",
+        nonce: "anything", // Optional, handed back if the packet can't be delivered
         data: {
             type: "send type",
             // Any data that needs to be sent
         }
     },
 
+    // Delivery failed packets
+    // These packets are sent from the core when a send packet couldn't reach its target
+    {
+        type: "delivery_failed",
+        id: 42, // The ID the core gave the packet
+        nonce: "anything", // The nonce from the send packet, null if there wasn't one
+        target: "my_fancy_component",
+        reason: "target not found", // Or "target has exited"
+    },
+
+    // Request packets
+    // These packets are sent when a component wants an answer from another component
+    // The core gives the request an ID and passes it on to the target without the nonce
+    {
+        type: "request",
+        target: "my_fancy_interface",
+        nonce: "anything", // Optional, handed back in the response so the caller can tell its responses apart
+        timeout: 10000, // Optional, how long to wait for a response in milliseconds
+        data: {
+            // Any data that needs to be sent
+        }
+    },
+    // What the target receives
+    {
+        type: "request",
+        id: 42, // Must be put in the response
+        source: "my_fancy_plugin",
+        timeout: 10000, // How long the caller waits, a response after that is thrown away
+        data: {}
+    },
+
+    // Response packets
+    // The target answers a request with the same ID, and the core passes it back to the caller
+    // If the target doesn't exist or doesn't answer in time, the core answers with an error instead
+    {
+        type: "response",
+        id: 42,
+        data: {
+            // Any data that needs to be sent back
+        },
+        error: "timed out", // Only there if something went wrong
+        // The caller also receives its nonce and the source of the response
+    },
+
     // Intents packets
     // These packets are to let the core know what the component is trying to do and what data to send
-    // All these fields are required, but are only read in certain circumstances
+    // Events and commands are required, but are only read in certain circumstances
+    // If the packet is invalid, the core answers with an intents_error packet
     {
         type: "intents",
-        events: ["message", "explosion", "yeet", "channel_create"], // These only matter for plugins
+        version: 1, // Optional, the version of this packet the component speaks
+        components: ["interface_1"], // Optional, only receive events from these components. Leave empty for all.
+        messages: true, // Optional, whether to accept send packets from other components
+        events: ["message.*", "explosion", "yeet", "channel.create"], // These only matter for plugins
+        // This only matters for sniffers, and can be left out to sniff everything
+        sniffer: {
+            priority: 10, // Higher priorities sniff first
+            events: ["message.create"], // Events to sniff
+            destinations: ["interface_2"], // Targets of send packets to sniff
+        },
         commands: [{
-            name: "fancycommand",
+            name: "fancycommand", // No spaces or ":", the core uses ":" to namespace conflicting commands
             description: "A fancy command",
             options: [
                 {
                     name: "suit",
                     description: "Wear a suit",
                     type: "bool",
-                    required: false
+                    required: false,
+                    choices: [] // Optional, the values the option is limited to
                 }
-            ]
+            ],
+            // Optional, how often the command can be invoked. rate_limits in the config replaces this.
+            rate_limit: {
+                count: 3, // Invocations allowed
+                window: 60, // Within this many seconds
+                scope: "user", // "user", "channel" or "global", invocations without a user or channel in their context share one allowance
+            },
+            // Optional, the permission a user needs to invoke the command. permissions.commands in the config replaces this.
+            permission: "moderation",
         }]
     },
+    {
+        type: "intents_error",
+        field: "commands[0].name", // The field that was wrong
+        message: "\"fancy command\" is not a valid command name",
+    },
+
+    // Command conflict packets
+    // These packets are sent from the core to a component that lost a command name to another component
+    // Who wins is decided by command_conflicts ("first_wins" or "namespace") and command_overrides in the config
+    {
+        type: "command_conflict",
+        name: "fancycommand",
+        winner: "other_plugin", // null if every conflicting command was namespaced
+        renamed: "my_fancy_plugin:fancycommand", // Namespaced with the ID of the component, null if the command was dropped
+    },
+
+    // Commands packets
+    // These packets are sent from the core to an interface with every command it should register
+    // An interface only gets this the first time, or when it sends one to the core to ask for the whole list again
+    {
+        type: "commands",
+        // The same as the commands in the intents packet
+        // Aliases and macros from the config are in here too, with alias set to the command they run
+        // Macros leave out the options they fill in themselves
+        commands: [{
+            name: "fs",
+            plugin: "plugin_name",
+            description: "Does something fancy in a suit",
+            options: [],
+            alias: "fancycommand",
+        }],
+    },
+
+    // Commands diff packets
+    // After the first list, interfaces that listed commands_diff in their hello only get told what changed, and nothing if nothing changed
+    // Other interfaces get the whole commands packet again whenever something changed
+    {
+        type: "commands_diff",
+        added: [], // New commands
+        removed: ["oldcommand"], // Names of commands that are gone
+        changed: [], // Commands that are still there but aren't the same anymore
+    },
+
+    // Commands ack packets
+    // These packets are sent from an interface to the core once it has registered what it was last sent
+    // The core caches what each interface has, so after a restart it only gets a diff instead of the whole list
+    // Interfaces that never send this get the whole list every time the core starts
+    {
+        type: "commands_ack",
+    },
+
+    // Invoke packets
+    // These packets are sent from an interface to the core when a user runs a command
+    // The core checks the options against the command and passes it on to the plugin that registered it
+    {
+        type: "invoke",
+        name: "fancycommand",
+        options: {
+            suit: true,
+        },
+        nonce: "anything", // Optional, handed back in any error
+        context: { // Optional, anything the plugin might want to know about where the command came from
+            user: "jkcoxson", // Used for per-user rate limits and permissions
+            channel: "general", // Used for per-channel rate limits
+        },
+    },
+    // What the plugin receives
+    {
+        type: "invoke",
+        name: "fancycommand", // The name the plugin registered, even if interfaces know it by a namespaced one
+        options: {
+            suit: true,
+        },
+        interface: "interface_1", // Where to send the reply
+        nonce: "anything",
+        context: {},
+    },
+    // What the interface receives if the command is unknown or the options are wrong
+    {
+        type: "invoke_error",
+        name: "fancycommand",
+        nonce: "anything",
+        reason: "missing required option suit",
+    },
+
+    // Reply packets
+    // These are sent from the core to an interface to answer a command the core handles itself, such as help
+    {
+        type: "event",
+        event: "reply",
+        data: {
+            message: "!help [command:string] - Shows how to use a command, or lists every command",
+            nonce: "anything", // From the invoke or text packet
+            context: {}, // From the invoke or text packet
+        },
+    },
 
-    // Command packets
-    // These packets are sent from an interface to the core to execute a command, and then to the plugin that created it
+    // Cooldown packets
+    // These are sent from the core to an interface instead of invoking a command that has hit its rate limit
+    // Only invocations that made it to the plugin count against the limit, ones that failed with an invoke_error don't
     {
-        type: "command",
+        type: "cooldown",
         name: "fancycommand",
-        // Any fields after this are up to the interface to define
-        data: "anything can go here, the plugin must know what to expect"
+        nonce: "anything",
+        retry_after: 12.5, // Seconds until it can be invoked again
+    },
+
+    // Permission denied packets
+    // These are sent from the core to an interface instead of invoking a command the user doesn't have a role for
+    // Roles are given to users per interface in the config, or from the Permissions menu
+    {
+        type: "permission_denied",
+        name: "fancycommand",
+        nonce: "anything",
+        permission: "moderation", // The permission the user is missing
+    },
+
+    // Text packets
+    // These packets are sent from an interface without its own commands, such as IRC, for every chat message
+    // If the message starts with command_prefix from the config, the core parses it and routes it like an invoke packet
+    // Named options use name=value, everything else fills the options in order, and quotes keep spaces together
+    // Anything else, including a prefix followed by a command that doesn't exist, is ignored
+    // Errors in a message for a command that does exist come back as invoke_error packets
+    {
+        type: "text",
+        message: "!fancycommand suit=yes",
+        nonce: "anything", // Optional, the same as in invoke packets
+        context: {}, // Optional, the same as in invoke packets
     },
 
     // ID packets
@@ -72,14 +336,21 @@ _ = [
     // Sniffer packets
     // These packets are sent if the component is a sniffer
     // This packet must be returned to the core for it to be passed on to the destination component
-    // If it is not returned, the packet will be dropped
+    // Once the last sniffer returns it, it is sent to the destination, or broadcast by its event if there is no destination
+    // If it is not returned in time (sniffer_timeout in the component's config), the packet skips the sniffer
+    // or is dropped, depending on the component's fail_open setting, and the same goes for packets a sniffer had when it exited
+    // Sniffers that have disconnected or stopped answering heartbeats are left out of the chain until they are back
+    // Only packet and drop are read back, changing where the packet came from or where it is going has no effect
     {
         type: "sniffer",
-        destination: "interface_2",
-        event: "",
+        id: 42, // No touchy, the core uses this to match up the returned packet
+        source: "interface_1", // No touchy, this is who sent the packet
+        destination: "interface_2", // No touchy
+        event: "", // No touchy
         sniffers: [], // No touchy, the core manages this. It is kept here for multi-threaded purposes.
-        packet: {} // The entire packet 
+        packet: {}, // The entire packet 
         // The packet may be mutilated or dropped, depending on the purpose of the sniffer
+        drop: false // Set to true (or set packet to null) to drop the packet
     },
 
     // Debug packets
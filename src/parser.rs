@@ -0,0 +1,269 @@
+// jkcoxson
+// Turns plain text messages into command invocations, for interfaces that don't have their own commands
+
+// A message looks like: !roll 20 dice=6 "some text" label="more text"
+// Only messages that start with the prefix and a registered command are parsed, anything else is people talking
+// Words without an = are positional and fill the options in order, skipping the ones given by name
+// Quotes keep spaces together, and \" puts a quote inside of them
+
+use serde_json::{Map, Value};
+
+use crate::commands::{CommandRegistry, CommandStructure, Option};
+
+// The command name and its options, or why they couldn't be parsed
+pub type Parsed = Result<(String, Map<String, Value>), ParseError>;
+
+/// Why a message couldn't be parsed
+pub struct ParseError {
+    pub name: String, // The command the message was for
+    pub message: String,
+}
+
+enum Argument {
+    Positional(String),
+    Named(String, String),
+}
+
+/// Parses a prefixed message into a command name and its options
+/// # Arguments
+/// * `message` - The message a user sent
+/// * `registry` - The registered commands, along with the prefix
+/// # Returns
+/// * None if the message isn't a command, otherwise the name and options or why they couldn't be parsed
+pub fn parse(message: &str, registry: &CommandRegistry) -> std::option::Option<Parsed> {
+    let message = message.trim().strip_prefix(registry.prefix.as_str())?;
+    let (name, rest) = message
+        .split_once(char::is_whitespace)
+        .unwrap_or((message, ""));
+    // Someone saying "!!!" or "!nope" isn't talking to us, so they don't get an error back
+    let command = registry.find(name)?;
+    let name = name.to_string();
+    let options = split(rest).and_then(|words| options(&command.structure, words));
+    Some(match options {
+        Ok(options) => Ok((name, options)),
+        Err(e) => Err(ParseError { name, message: e }),
+    })
+}
+
+/// Matches the arguments up with the options of the command
+fn options(
+    structure: &CommandStructure,
+    arguments: Vec<Argument>,
+) -> Result<Map<String, Value>, String> {
+    let mut options = Map::new();
+    let mut positional = vec![];
+    for argument in arguments {
+        match argument {
+            Argument::Named(name, value) => {
+                let option = match structure.options.iter().find(|o| o.name == name) {
+                    Some(option) => option,
+                    None => return Err(format!("unknown option {}", name)),
+                };
+                options.insert(name, coerce(option, &value)?);
+            }
+            Argument::Positional(value) => positional.push(value),
+        }
+    }
+
+    let remaining: Vec<&Option> = structure
+        .options
+        .iter()
+        .filter(|o| !options.contains_key(&o.name))
+        .collect();
+    let mut remaining = remaining.into_iter();
+    for value in positional {
+        let option = match remaining.next() {
+            Some(option) => option,
+            None => return Err(format!("too many arguments for {}", structure.name)),
+        };
+        options.insert(option.name.clone(), coerce(option, &value)?);
+    }
+    Ok(options)
+}
+
+/// Turns the text of an argument into the kind of value its option takes
+fn coerce(option: &Option, value: &str) -> Result<Value, String> {
+    // Choices can be given by name or by value
+    for choice in option.choices.iter() {
+        if choice.name == value || choice.value.as_str() == Some(value) {
+            return Ok(choice.value.clone());
+        }
+        // Numbers and such
+        if serde_json::from_str::<Value>(value).ok().as_ref() == Some(&choice.value) {
+            return Ok(choice.value.clone());
+        }
+    }
+
+    let invalid = || format!("option {} must be a {}", option.name, option.type_);
+    match option.type_.as_str() {
+        "integer" => value.parse::<i64>().map(Value::from).map_err(|_| invalid()),
+        "number" => value.parse::<f64>().map(Value::from).map_err(|_| invalid()),
+        "bool" | "boolean" => match value.to_lowercase().as_str() {
+            "true" | "yes" | "on" | "1" => Ok(Value::Bool(true)),
+            "false" | "no" | "off" | "0" => Ok(Value::Bool(false)),
+            _ => Err(invalid()),
+        },
+        _ => Ok(Value::String(value.to_string())),
+    }
+}
+
+/// Splits a message into arguments, keeping quoted text together
+fn split(message: &str) -> Result<Vec<Argument>, String> {
+    let mut arguments = vec![];
+    let mut chars = message.chars().peekable();
+    loop {
+        // Skip the space between arguments
+        while chars.peek().is_some_and(|c| c.is_whitespace()) {
+            chars.next();
+        }
+        if chars.peek().is_none() {
+            break;
+        }
+
+        let mut name = None;
+        let mut word = String::new();
+        let mut quoted = false;
+        while let Some(c) = chars.next() {
+            match c {
+                '"' => quoted = !quoted,
+                '\\' if quoted => match chars.next() {
+                    Some(escaped) => word.push(escaped),
+                    None => return Err("message ends with a \\".to_string()),
+                },
+                '=' if !quoted && name.is_none() && !word.is_empty() => {
+                    name = Some(word);
+                    word = String::new();
+                }
+                c if c.is_whitespace() && !quoted => break,
+                c => word.push(c),
+            }
+        }
+        if quoted {
+            return Err("message has an unclosed quote".to_string());
+        }
+        arguments.push(match name {
+            Some(name) => Argument::Named(name, word),
+            None => Argument::Positional(word),
+        });
+    }
+    Ok(arguments)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+    use serde_json::json;
+
+    fn registry() -> CommandRegistry {
+        let mut registry = CommandRegistry::new(&Config::new());
+        let roll = serde_json::from_value(json!({
+            "name": "roll",
+            "description": "Rolls some dice",
+            "options": [
+                {"name": "sides", "type": "integer", "description": "Sides on each die"},
+                {"name": "count", "type": "integer", "description": "How many dice"},
+                {"name": "label", "type": "string", "description": "What the roll is for"},
+                {"name": "loud", "type": "bool", "description": "Announce it"},
+                {"name": "weight", "type": "number", "description": "How heavy the dice are"},
+                {"name": "color", "type": "string", "description": "Color of the dice",
+                    "choices": [{"name": "red", "value": "r"}, {"name": "seven", "value": 7}]},
+            ],
+        }))
+        .unwrap();
+        registry.replace("dice", vec![roll]);
+        registry
+    }
+
+    fn ok(message: &str) -> Map<String, Value> {
+        match parse(message, &registry()) {
+            Some(Ok((name, options))) => {
+                assert_eq!(name, "roll");
+                options
+            }
+            Some(Err(e)) => panic!("{} failed to parse: {}", message, e.message),
+            None => panic!("{} is not a command", message),
+        }
+    }
+
+    fn err(message: &str) -> ParseError {
+        match parse(message, &registry()) {
+            Some(Err(e)) => e,
+            Some(Ok(_)) => panic!("{} parsed", message),
+            None => panic!("{} is not a command", message),
+        }
+    }
+
+    #[test]
+    fn positional_fill_in_order() {
+        let options = ok("!roll 20 3 \"for initiative\"");
+        assert_eq!(options["sides"], json!(20));
+        assert_eq!(options["count"], json!(3));
+        assert_eq!(options["label"], json!("for initiative"));
+    }
+
+    #[test]
+    fn positional_skip_named() {
+        let options = ok("!roll count=2 6 hi");
+        assert_eq!(options["count"], json!(2));
+        assert_eq!(options["sides"], json!(6));
+        assert_eq!(options["label"], json!("hi"));
+    }
+
+    #[test]
+    fn quotes_and_equals() {
+        let options = ok(r#"!roll label="a = \"b\"" 4"#);
+        assert_eq!(options["label"], json!("a = \"b\""));
+        assert_eq!(options["sides"], json!(4));
+        // Only the first = splits, and a quoted one never does
+        assert_eq!(ok("!roll label=x=y")["label"], json!("x=y"));
+        assert_eq!(ok("!roll 5 2 \"x=y\"")["label"], json!("x=y"));
+    }
+
+    #[test]
+    fn coerce_types() {
+        let options = ok("!roll loud=yes weight=1.5");
+        assert_eq!(options["loud"], json!(true));
+        assert_eq!(options["weight"], json!(1.5));
+        assert_eq!(ok("!roll loud=OFF")["loud"], json!(false));
+        assert_eq!(
+            err("!roll sides=many").message,
+            "option sides must be a integer"
+        );
+        assert_eq!(
+            err("!roll loud=maybe").message,
+            "option loud must be a bool"
+        );
+    }
+
+    #[test]
+    fn coerce_choices() {
+        assert_eq!(ok("!roll color=red")["color"], json!("r"));
+        assert_eq!(ok("!roll color=r")["color"], json!("r"));
+        assert_eq!(ok("!roll color=7")["color"], json!(7));
+        assert_eq!(ok("!roll color=seven")["color"], json!(7));
+    }
+
+    #[test]
+    fn errors_name_the_command() {
+        let e = err("!roll 1 2 3 true 1 red extra");
+        assert_eq!(e.name, "roll");
+        assert_eq!(e.message, "too many arguments for roll");
+        let e = err("!roll nope=1");
+        assert_eq!(e.name, "roll");
+        assert_eq!(e.message, "unknown option nope");
+        let e = err("!roll \"unclosed");
+        assert_eq!(e.name, "roll");
+        assert_eq!(e.message, "message has an unclosed quote");
+    }
+
+    #[test]
+    fn chatter_is_not_a_command() {
+        assert!(parse("hello there", &registry()).is_none());
+        assert!(parse("!", &registry()).is_none());
+        assert!(parse("!a=b", &registry()).is_none());
+        // Unknown commands are chatter too, even with a quote that never closes
+        assert!(parse("!flip", &registry()).is_none());
+        assert!(parse("!!! \"wow", &registry()).is_none());
+    }
+}
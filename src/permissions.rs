@@ -0,0 +1,96 @@
+// jkcoxson
+// Decides who can run which commands
+
+// Users are given roles per interface, since the same name on two chat servers might be two different people
+// Roles grant permissions, and commands can require a permission to be invoked
+// The "*" permission grants everything
+
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+
+#[derive(Serialize, Deserialize, Default)]
+pub struct Permissions {
+    // Role -> the permissions it grants
+    #[serde(default)]
+    pub roles: HashMap<String, Vec<String>>,
+    // Interface -> user -> their roles
+    #[serde(default)]
+    pub users: BTreeMap<String, BTreeMap<String, Vec<String>>>,
+    // Command name -> the permission needed to invoke it, replacing whatever the plugin asked for
+    // This is the name the plugin registered, so it also covers the command once it is namespaced and any aliases of it
+    #[serde(default)]
+    pub commands: HashMap<String, String>,
+}
+
+impl Permissions {
+    /// Whether a user has a permission
+    /// # Arguments
+    /// * `interface` - The interface the user is on
+    /// * `user` - The user, None if the interface didn't say who it was
+    /// * `permission` - The permission needed
+    pub fn allowed(&self, interface: &str, user: Option<&str>, permission: &str) -> bool {
+        let user = match user {
+            Some(user) => user,
+            None => return false,
+        };
+        let roles = match self.users.get(interface).and_then(|users| users.get(user)) {
+            Some(roles) => roles,
+            None => return false,
+        };
+        roles.iter().any(|role| match self.roles.get(role) {
+            Some(granted) => granted.iter().any(|p| p == permission || p == "*"),
+            None => false,
+        })
+    }
+
+    /// Gives a user a role on an interface
+    pub fn assign(&mut self, interface: &str, user: &str, role: &str) {
+        let roles = self
+            .users
+            .entry(interface.to_string())
+            .or_default()
+            .entry(user.to_string())
+            .or_default();
+        if !roles.iter().any(|r| r == role) {
+            roles.push(role.to_string());
+        }
+    }
+
+    /// Takes a role away from a user on an interface
+    pub fn unassign(&mut self, interface: &str, user: &str, role: &str) {
+        if let Some(users) = self.users.get_mut(interface) {
+            if let Some(roles) = users.get_mut(user) {
+                roles.retain(|r| r != role);
+                if roles.is_empty() {
+                    users.remove(user);
+                }
+            }
+            if users.is_empty() {
+                self.users.remove(interface);
+            }
+        }
+    }
+
+    /// Lists every role given to every user, as (interface, user, role)
+    pub fn assignments(&self) -> Vec<(String, String, String)> {
+        let mut assignments = vec![];
+        for (interface, users) in self.users.iter() {
+            for (user, roles) in users.iter() {
+                for role in roles.iter() {
+                    assignments.push((interface.clone(), user.clone(), role.clone()));
+                }
+            }
+        }
+        assignments
+    }
+}
+
+impl Clone for Permissions {
+    fn clone(&self) -> Permissions {
+        Permissions {
+            roles: self.roles.clone(),
+            users: self.users.clone(),
+            commands: self.commands.clone(),
+        }
+    }
+}
@@ -0,0 +1,153 @@
+// jkcoxson
+// Figures out who wants which events
+
+// Events are named hierarchically with dots, such as "message.create" or "message.delete"
+// Components can subscribe to:
+// "message.create" - exactly that event
+// "message.*" - every event under "message", however deep
+// "*" - every event
+// Anything else with a * in it, like "mess*", is rejected when the intents come in
+
+use std::collections::HashMap;
+
+use crate::component::Component;
+
+pub struct SubscriptionIndex {
+    exact: HashMap<String, Vec<String>>, // Event -> components subscribed to it
+    prefixes: HashMap<String, Vec<String>>, // "message." -> components subscribed to "message.*"
+    everything: Vec<String>,             // Components subscribed to "*"
+}
+
+impl SubscriptionIndex {
+    /// Builds the index from the intents of the cached components
+    pub fn new(components: &HashMap<String, Component>) -> SubscriptionIndex {
+        let mut index = SubscriptionIndex {
+            exact: HashMap::new(),
+            prefixes: HashMap::new(),
+            everything: vec![],
+        };
+        for (name, component) in components.iter() {
+            for pattern in component.intents.iter() {
+                if pattern == "*" {
+                    index.everything.push(name.clone());
+                } else if let Some(prefix) = pattern.strip_suffix('*') {
+                    index
+                        .prefixes
+                        .entry(prefix.to_string())
+                        .or_default()
+                        .push(name.clone());
+                } else {
+                    index
+                        .exact
+                        .entry(pattern.clone())
+                        .or_default()
+                        .push(name.clone());
+                }
+            }
+        }
+        index
+    }
+
+    /// Gets every component subscribed to an event, without duplicates
+    pub fn subscribers(&self, event: &str) -> Vec<String> {
+        let mut found = self.everything.clone();
+        if let Some(names) = self.exact.get(event) {
+            found.extend(names.iter().cloned());
+        }
+        // Check each level of the event, "a.b.c" checks "a." and "a.b."
+        for (i, c) in event.char_indices() {
+            if c == '.' {
+                if let Some(names) = self.prefixes.get(&event[..=i]) {
+                    found.extend(names.iter().cloned());
+                }
+            }
+        }
+        found.sort();
+        found.dedup();
+        found
+    }
+}
+
+/// Checks that a subscription pattern is one the index knows how to look up
+/// # Returns
+/// * Why the pattern would never match anything, if it wouldn't
+pub fn validate(pattern: &str) -> Result<(), String> {
+    if pattern == "*" {
+        return Ok(());
+    }
+    let name = pattern.strip_suffix(".*").unwrap_or(pattern);
+    if name.is_empty() || name.contains('*') {
+        return Err(format!(
+            "\"{}\" is not a valid pattern, wildcards only work as \"*\" or after a dot like \"message.*\"",
+            pattern
+        ));
+    }
+    Ok(())
+}
+
+/// Whether an event matches a subscription pattern
+pub fn matches(pattern: &str, event: &str) -> bool {
+    if pattern == "*" {
+        return true;
+    }
+    match pattern.strip_suffix('*') {
+        Some(prefix) => prefix.ends_with('.') && event.starts_with(prefix),
+        None => pattern == event,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::sync::mpsc::unbounded_channel;
+
+    const EVENTS: &[&str] = &["a", "a.b", "a.b.c", "a.b.c.d", "ab.c", "b", "b.a", "a."];
+
+    fn index(patterns: &[&str]) -> SubscriptionIndex {
+        let mut components = HashMap::new();
+        for pattern in patterns {
+            let (sender, _) = unbounded_channel();
+            let mut component = Component::new(pattern.to_string(), 1, "".to_string(), sender);
+            component.intents = vec![pattern.to_string()];
+            components.insert(pattern.to_string(), component);
+        }
+        SubscriptionIndex::new(&components)
+    }
+
+    #[test]
+    fn subscribers_agree_with_matches() {
+        let patterns = ["*", "a.*", "a.b.*", "a.b.c", "b", "a"];
+        let index = index(&patterns);
+        for event in EVENTS {
+            let mut expected: Vec<String> = patterns
+                .iter()
+                .filter(|pattern| matches(pattern, event))
+                .map(|pattern| pattern.to_string())
+                .collect();
+            expected.sort();
+            assert_eq!(index.subscribers(event), expected, "event {}", event);
+        }
+    }
+
+    #[test]
+    fn deep_names_match_every_level() {
+        let index = index(&["a.*", "a.b.*"]);
+        assert_eq!(index.subscribers("a.b.c"), vec!["a.*", "a.b.*"]);
+        assert_eq!(index.subscribers("a.b"), vec!["a.*"]);
+        assert!(index.subscribers("a").is_empty());
+        assert!(index.subscribers("ab.c").is_empty());
+        assert!(matches("a.*", "a.b.c.d"));
+        assert!(!matches("a.*", "a"));
+        assert!(!matches("a.*", "ab.c"));
+    }
+
+    #[test]
+    fn validate_rejects_dead_patterns() {
+        for pattern in ["*", "a", "a.b", "a.*", "a.b.*"] {
+            assert!(validate(pattern).is_ok(), "{}", pattern);
+        }
+        for pattern in ["mess*", "a.b*", "*.a", "a.*.b", ".*", "**", ""] {
+            assert!(validate(pattern).is_err(), "{}", pattern);
+        }
+    }
+}
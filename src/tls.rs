@@ -0,0 +1,227 @@
+// jkcoxson
+// Encrypts the connections of TCP components
+
+// TLS is only used if the config has a tls section, otherwise TCP components connect like they always have
+// Connections from this machine can skip TLS, since nothing they send leaves it
+// If client_ca is set, components can present a certificate signed by it instead of answering the key challenge
+// The common name of the certificate is looked up in client_names to find out which component it is
+
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    io,
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+    time::Duration,
+};
+#[cfg(unix)]
+use tokio::net::UnixStream;
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf},
+    net::TcpStream,
+};
+use tokio_rustls::{
+    rustls::{
+        crypto::ring,
+        pki_types::{pem::PemObject, CertificateDer, PrivateKeyDer},
+        server::WebPkiClientVerifier,
+        RootCertStore, ServerConfig,
+    },
+    server::TlsStream,
+    TlsAcceptor,
+};
+
+// How long a connection from this machine gets to start a TLS handshake before it is treated as plain TCP
+const LOOPBACK_SNIFF: Duration = Duration::from_millis(250);
+
+// How long a connection gets to finish the TLS handshake, so one that stalls doesn't stay open forever
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+// The first byte of every TLS handshake
+const TLS_HANDSHAKE: u8 = 0x16;
+
+#[derive(Serialize, Deserialize)]
+pub struct TlsConfig {
+    pub cert: String, // Path to the PEM certificate chain of the core
+    pub key: String,  // Path to the PEM private key of the core
+    // Path to the PEM certificates of the authority that signs component certificates
+    #[serde(default)]
+    pub client_ca: Option<String>,
+    // Certificate common name -> the component it belongs to
+    #[serde(default)]
+    pub client_names: HashMap<String, String>,
+    // Whether connections from this machine can skip TLS
+    #[serde(default = "default_loopback_plaintext")]
+    pub loopback_plaintext: bool,
+}
+
+fn default_loopback_plaintext() -> bool {
+    true
+}
+
+impl Clone for TlsConfig {
+    fn clone(&self) -> TlsConfig {
+        TlsConfig {
+            cert: self.cert.clone(),
+            key: self.key.clone(),
+            client_ca: self.client_ca.clone(),
+            client_names: self.client_names.clone(),
+            loopback_plaintext: self.loopback_plaintext,
+        }
+    }
+}
+
+/// A connection from a network component, over TCP, TLS or a Unix socket
+pub enum Connection {
+    Plain(TcpStream),
+    Tls(Box<TlsStream<TcpStream>>),
+    #[cfg(unix)]
+    Unix(UnixStream),
+}
+
+/// Accepts TLS connections the way the config says to
+pub struct Tls {
+    acceptor: TlsAcceptor,
+    client_names: HashMap<String, String>,
+    loopback_plaintext: bool,
+}
+
+impl Tls {
+    /// Loads the certificates and keys from the config
+    /// # Returns
+    /// * The acceptor, or why the files couldn't be used
+    pub fn new(config: &TlsConfig) -> Result<Tls, String> {
+        let certs = CertificateDer::pem_file_iter(&config.cert)
+            .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+            .map_err(|e| format!("failed to read {}: {}", config.cert, e))?;
+        let key = PrivateKeyDer::from_pem_file(&config.key)
+            .map_err(|e| format!("failed to read {}: {}", config.key, e))?;
+
+        let provider = Arc::new(ring::default_provider());
+        let builder = ServerConfig::builder_with_provider(provider.clone())
+            .with_safe_default_protocol_versions()
+            .map_err(|e| e.to_string())?;
+        let builder = match &config.client_ca {
+            Some(client_ca) => {
+                let mut roots = RootCertStore::empty();
+                for cert in CertificateDer::pem_file_iter(client_ca)
+                    .map_err(|e| format!("failed to read {}: {}", client_ca, e))?
+                {
+                    let cert = cert.map_err(|e| format!("failed to read {}: {}", client_ca, e))?;
+                    roots
+                        .add(cert)
+                        .map_err(|e| format!("bad certificate in {}: {}", client_ca, e))?;
+                }
+                // Components without a certificate can still answer the key challenge
+                let verifier =
+                    WebPkiClientVerifier::builder_with_provider(Arc::new(roots), provider)
+                        .allow_unauthenticated()
+                        .build()
+                        .map_err(|e| e.to_string())?;
+                builder.with_client_cert_verifier(verifier)
+            }
+            None => builder.with_no_client_auth(),
+        };
+        let server_config = builder
+            .with_single_cert(certs, key)
+            .map_err(|e| e.to_string())?;
+
+        Ok(Tls {
+            acceptor: TlsAcceptor::from(Arc::new(server_config)),
+            client_names: config.client_names.clone(),
+            loopback_plaintext: config.loopback_plaintext,
+        })
+    }
+
+    /// Does the TLS handshake with a new connection, unless it is from this machine and doesn't want to
+    /// # Returns
+    /// * The connection, the component its certificate belongs to if it sent one we know, or why the handshake failed
+    pub async fn accept(&self, socket: TcpStream) -> Result<(Connection, Option<String>), String> {
+        let loopback = socket
+            .peer_addr()
+            .map(|address| address.ip().is_loopback())
+            .unwrap_or(false);
+        if loopback && self.loopback_plaintext && !starts_tls(&socket).await {
+            return Ok((Connection::Plain(socket), None));
+        }
+
+        let stream =
+            match tokio::time::timeout(HANDSHAKE_TIMEOUT, self.acceptor.accept(socket)).await {
+                Ok(stream) => stream.map_err(|e| format!("TLS handshake failed: {}", e))?,
+                Err(_) => return Err("TLS handshake timed out".to_string()),
+            };
+        let name = stream
+            .get_ref()
+            .1
+            .peer_certificates()
+            .and_then(|certs| certs.first())
+            .and_then(|cert| common_name(cert))
+            .and_then(|name| self.client_names.get(&name).cloned());
+        Ok((Connection::Tls(Box::new(stream)), name))
+    }
+}
+
+/// Whether a connection opens with a TLS handshake
+/// Components only speak after the core sends its challenge, so anything that speaks first is TLS or an old component
+async fn starts_tls(socket: &TcpStream) -> bool {
+    let mut first = [0u8; 1];
+    match tokio::time::timeout(LOOPBACK_SNIFF, socket.peek(&mut first)).await {
+        Ok(Ok(1)) => first[0] == TLS_HANDSHAKE,
+        _ => false,
+    }
+}
+
+fn common_name(cert: &CertificateDer) -> Option<String> {
+    let (_, cert) = x509_parser::parse_x509_certificate(cert.as_ref()).ok()?;
+    let name = cert.subject().iter_common_name().next()?;
+    name.as_str().ok().map(|name| name.to_string())
+}
+
+impl AsyncRead for Connection {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Connection::Plain(stream) => Pin::new(stream).poll_read(cx, buf),
+            Connection::Tls(stream) => Pin::new(stream).poll_read(cx, buf),
+            #[cfg(unix)]
+            Connection::Unix(stream) => Pin::new(stream).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for Connection {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            Connection::Plain(stream) => Pin::new(stream).poll_write(cx, buf),
+            Connection::Tls(stream) => Pin::new(stream).poll_write(cx, buf),
+            #[cfg(unix)]
+            Connection::Unix(stream) => Pin::new(stream).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Connection::Plain(stream) => Pin::new(stream).poll_flush(cx),
+            Connection::Tls(stream) => Pin::new(stream).poll_flush(cx),
+            #[cfg(unix)]
+            Connection::Unix(stream) => Pin::new(stream).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Connection::Plain(stream) => Pin::new(stream).poll_shutdown(cx),
+            Connection::Tls(stream) => Pin::new(stream).poll_shutdown(cx),
+            #[cfg(unix)]
+            Connection::Unix(stream) => Pin::new(stream).poll_shutdown(cx),
+        }
+    }
+}
@@ -6,15 +6,20 @@ use cursive::theme::{self};
 use cursive::traits::{Boxable, Nameable, Scrollable};
 use cursive::{CursiveExt, With};
 use std::{collections::HashMap, sync::Arc};
-use tokio::net::TcpStream;
 use tokio::sync::Mutex;
 
-use cursive::views::{Dialog, EditView, OnEventView, SelectView, TextView};
+use cursive::views::{Dialog, EditView, LinearLayout, OnEventView, SelectView, TextView};
 use cursive::Cursive;
 
-use crate::config::ComponentConstructor;
+use crate::config::{
+    default_fail_open, default_heartbeat_interval, default_missed_heartbeats,
+    default_sniffer_timeout, ComponentConstructor, DeadPolicy,
+};
 use crate::constants;
-use crate::{commands::Command, component::Component, config, create_component, packet::Packet};
+use crate::{
+    commands::CommandRegistry, component::Component, config, create_component, packet::Packet,
+    permissions::Permissions, tls::Connection,
+};
 
 pub struct UI {
     pub messages: Vec<String>,
@@ -73,8 +78,8 @@ impl Logger {
 pub fn tui(
     logger: Arc<std::sync::Mutex<UI>>,
     component_arc: Arc<Mutex<HashMap<String, Component>>>,
-    command_arc: Arc<Mutex<Vec<Command>>>,
-    network_arc: Arc<Mutex<HashMap<String, tokio::sync::mpsc::UnboundedSender<TcpStream>>>>,
+    command_arc: Arc<Mutex<CommandRegistry>>,
+    network_arc: Arc<Mutex<HashMap<String, tokio::sync::mpsc::UnboundedSender<Connection>>>>,
     config: config::Config,
 ) {
     // Create the cursive TUI
@@ -188,8 +193,8 @@ fn display_menu(
     siv: &mut Cursive,
     logger: crate::ui::Logger,
     component_arc: Arc<Mutex<HashMap<String, Component>>>,
-    command_arc: Arc<Mutex<Vec<Command>>>,
-    network_arc: Arc<Mutex<HashMap<String, tokio::sync::mpsc::UnboundedSender<TcpStream>>>>,
+    command_arc: Arc<Mutex<CommandRegistry>>,
+    network_arc: Arc<Mutex<HashMap<String, tokio::sync::mpsc::UnboundedSender<Connection>>>>,
     config: config::Config,
 ) {
     siv.pop_layer();
@@ -198,6 +203,8 @@ fn display_menu(
     let remove_arc = component_arc.clone();
     let reload_arc = component_arc.clone();
     let quit_arc = component_arc.clone();
+    let permission_arc = command_arc.clone();
+    let health_arc = component_arc.clone();
 
     siv.add_layer(
         Dialog::around(Dialog::text(format!(
@@ -225,6 +232,12 @@ fn display_menu(
         .button("Reload Component", move |s| {
             choose_component_reload(s, reload_arc.clone())
         })
+        .button("Component Health", move |s| {
+            display_health(s, health_arc.clone())
+        })
+        .button("Permissions", move |s| {
+            choose_permission_removal(s, permission_arc.clone())
+        })
         .button("Exit", move |s| {
             // Kill all components
             let quit_arc = quit_arc.clone();
@@ -260,7 +273,7 @@ fn display_log(siv: &mut Cursive, messages: Vec<String>) {
     let mut line_wrap = 0;
     for i in messages {
         // Calculate the line wrap
-        let line_multiplier: usize = &i.len() / usize::from(screen_x);
+        let line_multiplier: usize = i.len() / usize::from(screen_x);
         line_wrap += line_multiplier;
 
         to_print.push(i);
@@ -284,8 +297,8 @@ fn choose_component_type(
     siv: &mut Cursive,
     logger: crate::ui::Logger,
     component_arc: Arc<Mutex<HashMap<String, Component>>>,
-    command_arc: Arc<Mutex<Vec<Command>>>,
-    network_arc: Arc<Mutex<HashMap<String, tokio::sync::mpsc::UnboundedSender<TcpStream>>>>,
+    command_arc: Arc<Mutex<CommandRegistry>>,
+    network_arc: Arc<Mutex<HashMap<String, tokio::sync::mpsc::UnboundedSender<Connection>>>>,
     config: config::Config,
 ) {
     siv.pop_layer();
@@ -347,8 +360,8 @@ fn choose_name(
     type_: u8,
     logger: crate::ui::Logger,
     component_arc: Arc<Mutex<HashMap<String, Component>>>,
-    command_arc: Arc<Mutex<Vec<Command>>>,
-    network_arc: Arc<Mutex<HashMap<String, tokio::sync::mpsc::UnboundedSender<TcpStream>>>>,
+    command_arc: Arc<Mutex<CommandRegistry>>,
+    network_arc: Arc<Mutex<HashMap<String, tokio::sync::mpsc::UnboundedSender<Connection>>>>,
     config: config::Config,
 ) {
     let pack = (
@@ -411,14 +424,15 @@ fn choose_name(
     );
 }
 
+#[allow(clippy::too_many_arguments)]
 fn choose_command(
     siv: &mut Cursive,
     type_: u8,
     name: String,
     logger: crate::ui::Logger,
     component_arc: Arc<Mutex<HashMap<String, Component>>>,
-    command_arc: Arc<Mutex<Vec<Command>>>,
-    network_arc: Arc<Mutex<HashMap<String, tokio::sync::mpsc::UnboundedSender<TcpStream>>>>,
+    command_arc: Arc<Mutex<CommandRegistry>>,
+    network_arc: Arc<Mutex<HashMap<String, tokio::sync::mpsc::UnboundedSender<Connection>>>>,
     config: config::Config,
 ) {
     siv.pop_layer();
@@ -484,6 +498,7 @@ fn choose_command(
     );
 }
 
+#[allow(clippy::too_many_arguments)]
 fn collect_component_options(
     siv: &mut Cursive,
     type_: u8,
@@ -491,8 +506,8 @@ fn collect_component_options(
     command: String,
     logger: crate::ui::Logger,
     component_arc: Arc<Mutex<HashMap<String, Component>>>,
-    command_arc: Arc<Mutex<Vec<Command>>>,
-    network_arc: Arc<Mutex<HashMap<String, tokio::sync::mpsc::UnboundedSender<TcpStream>>>>,
+    command_arc: Arc<Mutex<CommandRegistry>>,
+    network_arc: Arc<Mutex<HashMap<String, tokio::sync::mpsc::UnboundedSender<Connection>>>>,
     config: config::Config,
 ) {
     siv.pop_layer();
@@ -502,6 +517,11 @@ fn collect_component_options(
         name,
         type_,
         key: "".to_string(),
+        sniffer_timeout: default_sniffer_timeout(),
+        fail_open: default_fail_open(),
+        heartbeat_interval: default_heartbeat_interval(),
+        missed_heartbeats: default_missed_heartbeats(),
+        on_dead: DeadPolicy::default(),
     };
     let pack = (
         logger.clone("core".to_string()),
@@ -524,7 +544,7 @@ fn choose_component_removal(
     let cloned_component_arc = component_arc.clone();
 
     let list = get_component_list(component_arc);
-    if list.len() == 0 {
+    if list.is_empty() {
         siv.add_layer(Dialog::info("No components to remove"));
         return;
     }
@@ -538,15 +558,11 @@ fn choose_component_removal(
             tokio::spawn(async move {
                 // Send kill the component
                 let mut lock = cloned_component_arc.lock().await;
-                match lock.get_mut(choice.as_str()).unwrap().sender.send(Packet {
-                    source: "core".to_string(),
-                    destination: "".to_string(),
-                    event: "".to_string(),
-                    data: "kill".to_string(),
-                    sniffers: vec![],
-                }) {
-                    _ => {}
-                }
+                let _ = lock
+                    .get_mut(choice.as_str())
+                    .unwrap()
+                    .sender
+                    .send(Packet::control("core", "kill"));
             });
             s.pop_layer();
         });
@@ -576,7 +592,7 @@ fn choose_component_reload(
     let cloned_component_arc = component_arc.clone();
 
     let list = get_component_list(component_arc);
-    if list.len() == 0 {
+    if list.is_empty() {
         siv.add_layer(Dialog::info("No components to reload"));
         return;
     }
@@ -590,15 +606,11 @@ fn choose_component_reload(
             tokio::spawn(async move {
                 // Send kill the component
                 let mut lock = cloned_component_arc.lock().await;
-                match lock.get_mut(choice.as_str()).unwrap().sender.send(Packet {
-                    source: "core".to_string(),
-                    destination: "".to_string(),
-                    event: "".to_string(),
-                    data: "reload".to_string(),
-                    sniffers: vec![],
-                }) {
-                    _ => {}
-                }
+                let _ = lock
+                    .get_mut(choice.as_str())
+                    .unwrap()
+                    .sender
+                    .send(Packet::control("core", "reload"));
             });
             s.pop_layer();
         });
@@ -620,6 +632,154 @@ fn choose_component_reload(
     );
 }
 
+// Component health functions
+fn display_health(siv: &mut Cursive, component_arc: Arc<Mutex<HashMap<String, Component>>>) {
+    let list = get_health_list(component_arc);
+    if list.is_empty() {
+        siv.add_layer(Dialog::info("No components are running"));
+        return;
+    }
+    siv.add_layer(
+        Dialog::around(TextView::new(list.join("\n")).scrollable())
+            .title("Component Health")
+            .button("Back", |s| {
+                s.pop_layer();
+            }),
+    );
+}
+
+fn get_health_list(component_arc: Arc<Mutex<HashMap<String, Component>>>) -> Vec<String> {
+    let (tx, rx) = std::sync::mpsc::channel();
+    tokio::spawn(async move {
+        let mut list = Vec::new();
+        let component_arc = component_arc.lock().await;
+        for (name, component) in component_arc.iter() {
+            let health = if !component.gucci {
+                "not connected or not responding"
+            } else if component.capabilities.has("heartbeat") {
+                "healthy"
+            } else {
+                "running, no heartbeats"
+            };
+            list.push(format!("{}: {}", name, health));
+        }
+        list.sort();
+        tx.send(list).unwrap();
+    });
+    rx.recv().unwrap()
+}
+
+// Permission functions
+fn choose_permission_removal(siv: &mut Cursive, command_arc: Arc<Mutex<CommandRegistry>>) {
+    let cloned_command_arc = command_arc.clone();
+    let add_arc = command_arc.clone();
+
+    let list = get_assignment_list(command_arc);
+
+    let mut select = SelectView::new()
+        .h_align(HAlign::Center)
+        .autojump()
+        .on_submit(move |s, choice: &(String, String, String)| {
+            let cloned_command_arc = cloned_command_arc.clone();
+            let (interface, user, role) = choice.clone();
+            tokio::spawn(async move {
+                let mut lock = cloned_command_arc.lock().await;
+                lock.permissions.unassign(&interface, &user, &role);
+                save_permissions(lock.permissions.clone()).await;
+            });
+            s.pop_layer();
+        });
+    for assignment in list {
+        select.add_item(
+            format!("{} on {}: {}", assignment.1, assignment.0, assignment.2),
+            assignment,
+        );
+    }
+
+    let select = OnEventView::new(select)
+        .on_pre_event_inner('k', |s, _| {
+            let cb = s.select_up(1);
+            Some(EventResult::Consumed(Some(cb)))
+        })
+        .on_pre_event_inner('j', |s, _| {
+            let cb = s.select_down(1);
+            Some(EventResult::Consumed(Some(cb)))
+        });
+
+    siv.add_layer(
+        Dialog::around(select.scrollable().fixed_size((40, 10)))
+            .title("Which role would you like to take away?\n")
+            .button("Give Role", move |s| {
+                s.pop_layer();
+                choose_permission_assignment(s, add_arc.clone())
+            })
+            .button("Back", |s| {
+                s.pop_layer();
+            }),
+    );
+}
+
+fn choose_permission_assignment(siv: &mut Cursive, command_arc: Arc<Mutex<CommandRegistry>>) {
+    siv.add_layer(
+        Dialog::new()
+            .title("Give a user a role")
+            // Padding is (left, right, top, bottom)
+            .padding_lrtb(1, 1, 1, 0)
+            .content(
+                LinearLayout::vertical()
+                    .child(TextView::new("Interface"))
+                    .child(EditView::new().with_name("interface").fixed_width(30))
+                    .child(TextView::new("User"))
+                    .child(EditView::new().with_name("user").fixed_width(30))
+                    .child(TextView::new("Role"))
+                    .child(EditView::new().with_name("role").fixed_width(30)),
+            )
+            .button("Ok", move |s| {
+                let mut fields = vec![];
+                for name in ["interface", "user", "role"] {
+                    let content = s
+                        .call_on_name(name, |view: &mut EditView| view.get_content())
+                        .unwrap();
+                    fields.push(content.trim().to_string());
+                }
+                if fields.iter().any(|f| f.is_empty()) {
+                    s.add_layer(Dialog::info("Interface, user and role are all needed"));
+                    return;
+                }
+
+                let command_arc = command_arc.clone();
+                tokio::spawn(async move {
+                    let mut lock = command_arc.lock().await;
+                    lock.permissions.assign(&fields[0], &fields[1], &fields[2]);
+                    save_permissions(lock.permissions.clone()).await;
+                });
+                s.pop_layer();
+            })
+            .button("Cancel", |s| {
+                s.pop_layer();
+            }),
+    );
+}
+
+/// Writes the permissions to the config file, leaving everything else as it is on disk
+async fn save_permissions(permissions: Permissions) {
+    let mut config = match config::Config::load().await {
+        Some(config) => config,
+        None => config::Config::new(),
+    };
+    config.permissions = permissions;
+    config.save().await;
+}
+
+fn get_assignment_list(command_arc: Arc<Mutex<CommandRegistry>>) -> Vec<(String, String, String)> {
+    let (tx, rx) = std::sync::mpsc::channel();
+    tokio::spawn(async move {
+        tx.send(command_arc.lock().await.permissions.assignments())
+            .unwrap();
+    });
+    rx.recv().unwrap()
+}
+
 /// Wraps Tokio's mutex in a blocking function
 /// I don't know if this is really stupid or not
 /// Someone pls tell me if it's really stupid
@@ -638,6 +798,6 @@ fn get_component_list(component_arc: Arc<Mutex<HashMap<String, Component>>>) ->
 
 fn get_term_size() -> (u16, u16) {
     termsize::get()
-        .map(|size| return (size.cols - 2, size.rows - 2))
+        .map(|size| (size.cols - 2, size.rows - 2))
         .unwrap_or((80, 24))
 }
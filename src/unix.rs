@@ -0,0 +1,152 @@
+// jkcoxson
+// Lets components on this machine connect without opening a port
+
+// The socket is only created if the config has a socket section
+// Who can connect at all is up to the permissions of the socket file, which only the user running the core can use by default
+// The core asks the kernel which user is on the other end, and looks that user up in peers to find out which component it is
+// Users that aren't in peers have to answer the key challenge, the same as over TCP
+// A component that only connects as a peer doesn't need a key in the config
+
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    os::unix::fs::{DirBuilderExt, FileTypeExt, PermissionsExt},
+    path::{Path, PathBuf},
+};
+use tokio::net::UnixListener;
+
+use crate::tls::Connection;
+
+#[derive(Serialize, Deserialize)]
+pub struct SocketConfig {
+    pub path: String, // Where the socket file goes, an old socket there is replaced
+    // The permissions of the socket file, in octal
+    #[serde(default = "default_socket_mode")]
+    pub mode: String,
+    // User ID -> the component it belongs to
+    #[serde(default)]
+    pub peers: HashMap<u32, String>,
+}
+
+fn default_socket_mode() -> String {
+    "600".to_string()
+}
+
+impl Clone for SocketConfig {
+    fn clone(&self) -> SocketConfig {
+        SocketConfig {
+            path: self.path.clone(),
+            mode: self.mode.clone(),
+            peers: self.peers.clone(),
+        }
+    }
+}
+
+/// A Unix socket listening for components
+pub struct Socket {
+    listener: UnixListener,
+    peers: HashMap<u32, String>,
+}
+
+impl Socket {
+    /// Creates the socket file and gives it the permissions from the config
+    /// # Returns
+    /// * The socket, or why it couldn't be created
+    pub fn bind(config: &SocketConfig) -> Result<Socket, String> {
+        let mode = u32::from_str_radix(&config.mode, 8)
+            .map_err(|_| format!("\"{}\" is not an octal file mode", config.mode))?;
+
+        // A core that didn't shut down cleanly leaves its socket behind
+        let path = Path::new(&config.path);
+        if let Ok(metadata) = std::fs::symlink_metadata(path) {
+            if !metadata.file_type().is_socket() {
+                return Err(format!("{} exists and isn't a socket", config.path));
+            }
+            std::fs::remove_file(path)
+                .map_err(|e| format!("failed to remove the old {}: {}", config.path, e))?;
+        }
+
+        // The socket is made in a directory only we can get into, and moved into place once it has its permissions
+        // Otherwise anyone could connect in the moment it has whatever permissions the umask gave it
+        let staging = PathBuf::from(format!("{}.{}", config.path, std::process::id()));
+        std::fs::DirBuilder::new()
+            .mode(0o700)
+            .create(&staging)
+            .map_err(|e| format!("failed to create {}: {}", staging.display(), e))?;
+        let staged = staging.join("s");
+        let listener = UnixListener::bind(&staged)
+            .map_err(|e| format!("failed to bind {}: {}", config.path, e))
+            .and_then(|listener| {
+                std::fs::set_permissions(&staged, std::fs::Permissions::from_mode(mode)).map_err(
+                    |e| format!("failed to set the permissions of {}: {}", config.path, e),
+                )?;
+                std::fs::rename(&staged, path)
+                    .map_err(|e| format!("failed to move the socket to {}: {}", config.path, e))?;
+                Ok(listener)
+            });
+        // Don't care, whatever is left in there is of no use
+        let _ = std::fs::remove_file(&staged);
+        let _ = std::fs::remove_dir(&staging);
+        let listener = listener?;
+
+        Ok(Socket {
+            listener,
+            peers: config.peers.clone(),
+        })
+    }
+
+    /// Waits for a component to connect
+    /// # Returns
+    /// * The connection, the user on the other end, and the component that user belongs to if it is in peers
+    pub async fn accept(&self) -> Result<(Connection, u32, Option<String>), String> {
+        let (stream, _) = self
+            .listener
+            .accept()
+            .await
+            .map_err(|e| format!("failed to accept a connection: {}", e))?;
+        let uid = stream
+            .peer_cred()
+            .map_err(|e| format!("failed to get the credentials of a connection: {}", e))?
+            .uid();
+        let name = self.peers.get(&uid).cloned();
+        Ok((Connection::Unix(stream), uid, name))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn bind_sets_the_mode_and_cleans_up() {
+        let path = std::env::temp_dir().join(format!("camel_bot_{}.sock", std::process::id()));
+        let config = SocketConfig {
+            path: path.to_string_lossy().to_string(),
+            mode: "640".to_string(),
+            peers: HashMap::new(),
+        };
+        // Twice, so the second one has to replace the first
+        for _ in 0..2 {
+            let _socket = Socket::bind(&config).unwrap();
+            let metadata = std::fs::symlink_metadata(&path).unwrap();
+            assert!(metadata.file_type().is_socket());
+            assert_eq!(metadata.permissions().mode() & 0o777, 0o640);
+            assert!(!Path::new(&format!("{}.{}", config.path, std::process::id())).exists());
+        }
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn bind_refuses_to_replace_other_files() {
+        let path = std::env::temp_dir().join(format!("camel_bot_{}.txt", std::process::id()));
+        std::fs::write(&path, "not a socket").unwrap();
+        let config = SocketConfig {
+            path: path.to_string_lossy().to_string(),
+            mode: "600".to_string(),
+            peers: HashMap::new(),
+        };
+        assert!(Socket::bind(&config).is_err());
+        assert!(path.exists());
+        std::fs::remove_file(&path).unwrap();
+    }
+}